@@ -1,9 +1,13 @@
 mod solve;
+use crate::solve::solver_context::SolverContext;
 use std::convert::Infallible;
+use std::sync::Arc;
 use warp::{hyper::StatusCode, Filter, Rejection, Reply};
 
-pub fn handle_all_routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    let solve = solve::get_solve();
+pub fn handle_all_routes(
+    context: Arc<SolverContext>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let solve = solve::get_solve(context);
     let cors = warp::cors()
         .allow_any_origin()
         .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS", "PUT", "PATCH"])
@@ -1,6 +1,7 @@
 use crate::models::batch_auction_model::BatchAuctionModel;
 use crate::models::batch_auction_model::SettledBatchAuctionModel;
 use crate::solve;
+use crate::solve::solver_context::SolverContext;
 use anyhow::Result;
 use hex::{FromHex, FromHexError};
 use primitive_types::H160;
@@ -9,6 +10,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use std::convert::Infallible;
 use std::str::FromStr;
+use std::sync::Arc;
 use warp::{
     hyper::StatusCode,
     reply::{self, json, with_status, Json, WithStatus},
@@ -66,9 +68,14 @@ pub fn convert_get_solve_error_to_reply(err: anyhow::Error) -> WithStatus<Json>
     with_status(internal_error(err), StatusCode::INTERNAL_SERVER_ERROR)
 }
 
-pub fn get_solve() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    get_solve_request().and_then(move |model| async move {
-        let result = solve::solve(model).await;
-        Result::<_, Infallible>::Ok(get_solve_response(result))
+pub fn get_solve(
+    context: Arc<SolverContext>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    get_solve_request().and_then(move |model| {
+        let context = context.clone();
+        async move {
+            let result = solve::solve(model, &context).await;
+            Result::<_, Infallible>::Ok(get_solve_response(result))
+        }
     })
 }
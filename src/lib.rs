@@ -9,11 +9,13 @@ extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
 
+use solve::solver_context::SolverContext;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::{task, task::JoinHandle};
 
-pub fn serve_task(address: SocketAddr) -> JoinHandle<()> {
-    let filter = api::handle_all_routes();
+pub fn serve_task(address: SocketAddr, context: Arc<SolverContext>) -> JoinHandle<()> {
+    let filter = api::handle_all_routes(context);
     tracing::info!(%address, "serving api");
     task::spawn(warp::serve(filter).bind(address))
 }
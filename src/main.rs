@@ -1,14 +1,46 @@
 use cowdexsolver::serve_task;
+use cowdexsolver::solve::chain_config::ChainConfig;
+use cowdexsolver::solve::solver_context::SolverContext;
 use cowdexsolver::tracing_helper::initialize;
+use derivative::Derivative;
+use primitive_types::H160;
+use reqwest::Url;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use structopt::StructOpt;
 
-#[derive(Debug, StructOpt)]
+#[derive(Derivative, StructOpt)]
+#[derivative(Debug)]
 struct Arguments {
     #[structopt(long, env = "LOG_FILTER", default_value = "warn,debug,info")]
     pub log_filter: String,
     #[structopt(long, env = "BIND_ADDRESS", default_value = "127.0.0.1:8000")]
     bind_address: SocketAddr,
+    /// The Ethereum node used to look up token allowances and to encode interactions.
+    #[structopt(
+        long,
+        env = "NODE_URL",
+        default_value = "https://staging-openethereum.mainnet.gnosisdev.com"
+    )]
+    node_url: Url,
+    /// Overrides the GPv2 settlement contract of the selected chain.
+    #[structopt(long, env = "SETTLEMENT_CONTRACT_ADDRESS")]
+    settlement_contract_address: Option<H160>,
+    /// Selects the chain specific configuration (1: Mainnet, 5: Goerli, 100: Gnosis Chain).
+    #[structopt(long, env = "CHAIN_ID", default_value = "1")]
+    chain_id: u64,
+    /// Relative amount by which the limit price of an order may be worse than the external
+    /// prices for it to still count as a market order.
+    #[structopt(long, env = "MARKET_ORDER_TOLERANCE", default_value = "0.005")]
+    market_order_tolerance: f64,
+    /// Maximal number of user orders that are solved for per instance. The orders with the
+    /// highest scores are selected.
+    #[structopt(long, env = "ORDER_BUDGET", default_value = "10")]
+    order_budget: usize,
+    /// The api key sent with the requests to 0x.
+    #[structopt(long, env = "ZEROEX_API_KEY")]
+    #[derivative(Debug = "ignore")]
+    zeroex_api_key: Option<String>,
 }
 
 #[tokio::main]
@@ -16,7 +48,15 @@ async fn main() {
     let args = Arguments::from_args();
     initialize(args.log_filter.as_str());
     tracing::info!("running data-server with {:#?}", args);
-    let serve_task = serve_task(args.bind_address);
+    let mut chain = ChainConfig::for_chain_id(args.chain_id).expect("unsupported chain");
+    if let Some(settlement_contract) = args.settlement_contract_address {
+        chain.settlement_contract = settlement_contract;
+    }
+    let mut context = SolverContext::new(&args.node_url, chain, args.zeroex_api_key.clone())
+        .expect("failed to set up the solver context");
+    context.market_order_tolerance = args.market_order_tolerance;
+    context.order_budget = args.order_budget;
+    let serve_task = serve_task(args.bind_address, Arc::new(context));
     tokio::select! {
         result = serve_task => tracing::error!(?result, "serve task exited"),
     };
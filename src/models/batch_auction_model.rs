@@ -165,6 +165,9 @@ impl SettledBatchAuctionModel {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MetadataModel {
     pub environment: Option<String>,
+    /// Gas price in wei, used to estimate the costs of interactions.
+    #[serde(default)]
+    pub gas_price: Option<f64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -366,6 +369,7 @@ mod tests {
             },
             metadata: Some(MetadataModel {
                 environment: Some(String::from("Such Meta")),
+                gas_price: Some(15_000_000_000.0),
             }),
             instance_name: None,
             max_nr_exec_orders: None,
@@ -462,6 +466,7 @@ mod tests {
           },
           "metadata": {
             "environment": "Such Meta",
+            "gas_price": 15000000000.0,
           },
           "time_limit": null,
           "max_nr_exec_orders": null,
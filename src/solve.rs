@@ -1,30 +1,56 @@
+mod aggregator_error;
+mod amm_solver;
+pub mod chain_config;
+mod clearing_prices;
+mod deadline;
+mod market_order;
+mod objective;
+mod order_book_solver;
+mod order_prioritization;
 mod paraswap_solver;
+pub mod solver_context;
 mod solver_utils;
 mod zeroex_solver;
+use crate::models::batch_auction_model::ExecutedAmmModel;
 use crate::models::batch_auction_model::ExecutedOrderModel;
+use crate::models::batch_auction_model::ExecutionPlanCoordinatesModel;
 use crate::models::batch_auction_model::InteractionData;
+use crate::models::batch_auction_model::MetadataModel;
 use crate::models::batch_auction_model::OrderModel;
 use crate::models::batch_auction_model::SettledBatchAuctionModel;
+use crate::models::batch_auction_model::UpdatedAmmModel;
 use crate::models::batch_auction_model::{BatchAuctionModel, TokenInfoModel};
+use crate::solve::aggregator_error::{AggregatorError, FailurePolicy};
+use crate::solve::amm_solver::{AmmRoute, AmmRouter};
+use crate::solve::chain_config::ChainConfig;
+use crate::solve::clearing_prices::{compute_clearing_prices, ClearingPrices, TradeEdge};
+use crate::solve::deadline::{
+    collect_until_deadline, deadline_from_time_limit, discovery_deadline, order_book_deadline,
+};
+use crate::solve::order_book_solver::{CowMatch, Residual, RingMatch};
+use crate::solve::order_prioritization::prioritize_orders;
 use crate::solve::paraswap_solver::ParaswapSolver;
+use crate::solve::solver_context::SolverContext;
 use crate::token_list::get_buffer_tradable_token_list;
 use crate::token_list::BufferTradingTokenList;
 use crate::token_list::Token;
 
-use crate::solve::paraswap_solver::api::Root;
+use crate::solve::paraswap_solver::api::{ParaswapResponseError, PriceRoute, Root};
 use crate::solve::solver_utils::Slippage;
 use crate::solve::zeroex_solver::api::SwapQuery;
 use crate::solve::zeroex_solver::api::SwapResponse;
-use crate::solve::zeroex_solver::ZeroExSolver;
-use anyhow::{anyhow, Result};
+use crate::utils::conversions::{big_int_to_u256, big_rational_to_float, U256Ext};
+use anyhow::{anyhow, Context, Result};
 use ethcontract::batch::CallBatch;
 use ethcontract::prelude::*;
-use futures::future::join_all;
+use num::{BigRational, Integer};
 use primitive_types::{H160, U256};
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
-use std::env;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::future::Future;
+use tokio::time::{timeout_at, Instant};
 
 ethcontract::contract!("contracts/artifacts/ERC20.json");
 
@@ -34,29 +60,64 @@ lazy_static! {
 
 pub async fn solve(
     BatchAuctionModel {
-        orders, mut tokens, ..
+        orders,
+        tokens,
+        amms,
+        metadata,
+        time_limit,
+        max_nr_exec_orders,
+        ..
     }: BatchAuctionModel,
+    context: &SolverContext,
 ) -> Result<SettledBatchAuctionModel> {
+    let deadline = deadline_from_time_limit(time_limit);
+    let amm_router = AmmRouter::new(amms.clone(), &tokens);
     tracing::info!(
         "Before filtering: Solving instance with the orders {:?} and the tokens: {:?}",
         orders,
         tokens
     );
 
-    let api_key = env::var("ZEROEX_API_KEY").map(Some).unwrap_or(None);
     if orders.is_empty() {
         return Ok(SettledBatchAuctionModel::default());
     }
 
-    let mut orders: Vec<(usize, OrderModel)> = orders.into_iter().map(|(i, y)| (i, y)).collect();
-    // For simplicity, only solve for up to 10 orders
-    if orders.len() > 4usize {
-        orders = orders
-            .into_iter()
-            .filter(|(_, order)| is_market_order(&tokens, order.clone()).unwrap_or(false))
-            .collect();
+    // Orders are traded net of their fee, which the settlement collects on top of the
+    // executed sell amount.
+    let orders: Vec<(usize, OrderModel)> = orders
+        .into_iter()
+        .filter_map(|(index, order)| match order_net_of_fee(order) {
+            Ok(order) => Some((index, order)),
+            Err(err) => {
+                tracing::debug!("Dropping order {} due to its fee: {:?}", index, err);
+                None
+            }
+        })
+        .collect();
+    // Liquidity orders are never required to be executed, they are only used as counterparties
+    // of the user orders.
+    let (liquidity_orders, orders): (Vec<_>, Vec<_>) = orders
+        .into_iter()
+        .partition(|(_, order)| order.is_liquidity_order);
+    if orders.is_empty() {
+        return Ok(SettledBatchAuctionModel::default());
     }
-    orders.truncate(10);
+    let max_nr_exec_orders = max_nr_exec_orders.map_or(DEFAULT_MAX_NR_EXEC_ORDERS, |max| {
+        usize::try_from(max).unwrap_or(usize::MAX)
+    });
+    let tolerance = BigRational::from_float(context.market_order_tolerance)
+        .ok_or_else(|| anyhow!("invalid market order tolerance"))?;
+    let orders = prioritize_orders(
+        orders,
+        &tokens,
+        &tolerance,
+        max_nr_exec_orders.min(context.order_budget),
+    )
+    .selected;
+    if orders.is_empty() {
+        return Ok(SettledBatchAuctionModel::default());
+    }
+    let max_nr_liquidity_orders = max_nr_exec_orders.saturating_sub(orders.len());
 
     tracing::info!(
         "After filtering: Solving instance with the orders {:?} and the tokens: {:?}",
@@ -64,9 +125,88 @@ pub async fn solve(
         tokens
     );
 
+    // Step0: match opposite orders on the same token pair directly against each other, and
+    // orders trading in a ring over tokens without such matches. If this settles all orders,
+    // no aggregator needs to be queried.
+    // The residuals of the pairs are quoted one after the other, as the amm residuals change
+    // the reserves for the next pairs, so they only get a share of the time.
+    let order_book_deadline = order_book_deadline(deadline);
+    let mut order_book_router = amm_router.clone();
+    let mut cow_matches = Vec::new();
+    for ((token_a, token_b), pair_orders) in order_book_solver::orders_by_pair(&orders) {
+        let quote = |sell_token, buy_token, amount| {
+            quote_residual(
+                &order_book_router,
+                sell_token,
+                buy_token,
+                amount,
+                context,
+                order_book_deadline,
+            )
+        };
+        let cow_match =
+            order_book_solver::match_pair(token_a, token_b, &pair_orders, &tokens, quote).await;
+        if let Some(cow_match) = cow_match {
+            if let Some(Residual::Amm(route)) = &cow_match.residual {
+                order_book_router.execute(route);
+            }
+            cow_matches.push(cow_match);
+        }
+    }
+    let cow_tokens: BTreeSet<H160> = cow_matches
+        .iter()
+        .flat_map(|cow_match| [cow_match.sell_token, cow_match.buy_token])
+        .collect();
+    let ring_matches = order_book_solver::match_rings(
+        &orders
+            .iter()
+            .filter(|(_, order)| {
+                !cow_tokens.contains(&order.sell_token) && !cow_tokens.contains(&order.buy_token)
+            })
+            .cloned()
+            .collect::<Vec<_>>(),
+        &tokens,
+    );
+    let orders_on_other_pairs: Vec<(usize, OrderModel)> = orders
+        .iter()
+        .filter(|(index, _)| {
+            !cow_matches
+                .iter()
+                .any(|cow_match| cow_match.orders.iter().any(|(i, _)| i == index))
+                && !ring_matches
+                    .iter()
+                    .any(|ring_match| ring_match.orders.iter().any(|(i, _)| i == index))
+        })
+        .cloned()
+        .collect();
+    let order_book_settles_all_orders = cow_matches
+        .iter()
+        .map(|cow_match| cow_match.orders.len())
+        .chain(
+            ring_matches
+                .iter()
+                .map(|ring_match| ring_match.orders.len()),
+        )
+        .sum::<usize>()
+        == orders.len();
+
     // Step1: get splitted trade amounts per token pair for each order via paraswap dex-ag
-    let (matched_orders, single_trade_results) =
-        get_matchable_orders_and_subtrades(orders.clone(), tokens.clone()).await;
+    let ParaswapSubTrades {
+        matched_orders,
+        sub_trades: single_trade_results,
+        ..
+    } = if order_book_settles_all_orders {
+        ParaswapSubTrades::default()
+    } else {
+        // The left-over amounts still need to be traded on 0x after the discovery.
+        get_matchable_orders_and_subtrades(
+            orders.clone(),
+            tokens.clone(),
+            context,
+            discovery_deadline(deadline),
+        )
+        .await
+    };
     tracing::debug!("single_trade_results: {:?}", single_trade_results);
     let contains_cow = contain_cow(&single_trade_results);
     for sub_trade in single_trade_results.iter() {
@@ -77,142 +217,361 @@ pub async fn solve(
         );
     }
 
-    // 2nd step: Removing obvious cow volume from splitted traded amounts, by matching opposite volume
-    let ((matched_orders, mut swap_results), splitted_trade_amounts) = match contains_cow {
-        true => {
-            tracing::info!("Found cow and trying to solve it");
-
-            let splitted_trade_amounts =
-                get_splitted_trade_amounts_from_trading_vec(single_trade_results);
-            // if there is a cow volume, we try to remove it
-            let updated_traded_amounts =
-                match get_trade_amounts_without_cow_volumes(&splitted_trade_amounts) {
-                    Ok(traded_amounts) => traded_amounts,
-                    Err(err) => {
-                        tracing::debug!(
-                            "Error from zeroEx api for trade amounts without cows: {:?}",
-                            err
-                        );
-                        return Ok(SettledBatchAuctionModel::default());
-                    }
-                };
-
-            for (pair, entry_amounts) in &updated_traded_amounts {
-                tracing::debug!(
-                    " After cow merge: trade on pair {:?} with values {:?}",
-                    pair,
-                    entry_amounts,
-                );
+    // 2nd and 3rd step: Build the candidate settlements. The cows and rings of the order book
+    // are settled without interactions, while the orders on other pairs are settled directly.
+    // With cows among the sub trades, only the left-over amounts of the cows are traded.
+    // Settling every order directly against the amms and zeroEx is a candidate as well, since
+    // it might still be the better solution.
+    let order_book_candidate = async {
+        if cow_matches.is_empty() && ring_matches.is_empty() {
+            return None;
+        }
+        let mut candidate = get_direct_candidate(
+            orders_on_other_pairs,
+            &liquidity_orders,
+            max_nr_liquidity_orders,
+            order_book_router,
+            context,
+            deadline,
+        )
+        .await;
+        for cow_match in cow_matches {
+            candidate
+                .matched_orders
+                .extend(cow_match.orders.iter().cloned());
+            candidate
+                .swap_results
+                .push(LiquidityTrade::from_cow_match(cow_match));
+        }
+        for ring_match in ring_matches {
+            candidate
+                .matched_orders
+                .extend(ring_match.orders.iter().cloned());
+            candidate
+                .swap_results
+                .push(LiquidityTrade::from_ring_match(ring_match));
+        }
+        Some(candidate)
+    };
+    let cow_candidate = async {
+        if !contains_cow {
+            return None;
+        }
+        tracing::info!("Found cow and trying to solve it");
+        match get_cow_candidate(
+            matched_orders,
+            single_trade_results,
+            amm_router.clone(),
+            &tokens,
+            context,
+            deadline,
+        )
+        .await
+        {
+            Ok(candidate) => Some(candidate),
+            Err(err) => {
+                tracing::debug!("Solving the cows failed: {:?}", err);
+                None
             }
-
-            // 3rd step: Get trades from zeroEx of left-over amounts
-            let swap_results =
-                match get_swaps_for_left_over_amounts(updated_traded_amounts, api_key).await {
-                    Ok(swap_results) => swap_results,
-                    Err(err) => {
-                        tracing::debug!(
-                            "Error from zeroEx api for trading left over amounts: {:?}",
-                            err
-                        );
-                        return Ok(SettledBatchAuctionModel::default());
-                    }
-                };
-            ((matched_orders, swap_results), splitted_trade_amounts)
         }
-        false => {
-            tracing::info!("Falling back to normal zeroEx solver");
+    };
+    let direct_candidate = async {
+        if order_book_settles_all_orders {
+            return None;
+        }
+        Some(
+            get_direct_candidate(
+                orders.clone(),
+                &liquidity_orders,
+                max_nr_liquidity_orders,
+                amm_router.clone(),
+                context,
+                deadline,
+            )
+            .await,
+        )
+    };
+    let (order_book_candidate, cow_candidate, direct_candidate) =
+        futures::join!(order_book_candidate, cow_candidate, direct_candidate);
 
-            let zero_ex_results = match get_swaps_for_orders_from_zeroex(orders, api_key).await {
-                Ok(zero_ex_results) => zero_ex_results,
+    // 4th to 6th step: Build the settlements of all candidates and choose the best one
+    let gas_price = get_gas_price(metadata, context, deadline).await;
+    // Returning no solution has an objective of zero, which every candidate has to beat.
+    let mut best_solution = (0.0, SettledBatchAuctionModel::default());
+    for candidate in order_book_candidate
+        .into_iter()
+        .chain(cow_candidate)
+        .chain(direct_candidate)
+    {
+        // Executed liquidity orders are part of the solution as well, only their costs count.
+        let evaluated_orders: Vec<_> = candidate
+            .matched_orders
+            .iter()
+            .chain(&liquidity_orders)
+            .cloned()
+            .collect();
+        let solution = build_settlement(candidate, tokens.clone(), context, deadline).await;
+        let objective =
+            match objective::evaluate(&solution, &evaluated_orders, &amms, &tokens, gas_price) {
+                Ok(objective) => objective,
                 Err(err) => {
-                    tracing::debug!("Error while calling zeroEx api in fallback mode: {:?}", err);
-                    return Ok(SettledBatchAuctionModel::default());
+                    tracing::debug!("Could not evaluate candidate solution: {:?}", err);
+                    continue;
                 }
             };
-            (zero_ex_results.into_iter().unzip(), HashMap::new())
+        tracing::debug!(
+            "Candidate solution with objective {}: {:?}",
+            objective,
+            solution
+        );
+        if solution.orders.len() > max_nr_exec_orders {
+            tracing::debug!("Candidate solution executes too many orders");
+            continue;
         }
-    };
+        // On equal objectives, the earlier candidate is preferred.
+        if objective > best_solution.0 {
+            best_solution = (objective, solution);
+        }
+    }
+    let (_, solution) = best_solution;
+    tracing::info!("Found solution: {:?}", solution);
+    Ok(solution)
+}
 
-    // 4th step: Get all approvals via a batch requests for the different swap
-    let http = Http::new("https://staging-openethereum.mainnet.gnosisdev.com").unwrap();
-    let web3 = Web3::new(http);
-    let mut allowances = get_allowances_for_tokens_involved(&swap_results).await;
+/// Number of executed orders, if the instance doesn't limit it.
+const DEFAULT_MAX_NR_EXEC_ORDERS: usize = 10;
 
-    // 5th step: Build settlements with price and interactions
-    let mut solution = SettledBatchAuctionModel::default();
-    let tradable_buffer_token_list = get_buffer_tradable_token_list();
-    while !swap_results.is_empty() {
-        let (query, mut swap) = swap_results.pop().unwrap();
-        match insert_new_price(
-            &mut solution,
-            &splitted_trade_amounts,
-            query.clone(),
-            swap.clone(),
-        ) {
-            Ok(()) => {}
+/// The orders of a candidate settlement together with the trades settling them.
+struct Candidate {
+    matched_orders: Vec<(usize, OrderModel)>,
+    swap_results: Vec<LiquidityTrade>,
+    splitted_trade_amounts: HashMap<(H160, H160), (U256, U256)>,
+}
+
+/// Matches the cows among the sub trades of the orders and trades the left-over amounts
+/// against the amms of the instance, and against zeroEx for the ones the amms can not serve.
+/// Every order has to clear within its limit price at the resulting clearing prices.
+async fn get_cow_candidate(
+    matched_orders: Vec<(usize, OrderModel)>,
+    single_trade_results: Vec<SubTrade>,
+    mut amm_router: AmmRouter,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    context: &SolverContext,
+    deadline: Instant,
+) -> Result<Candidate> {
+    let splitted_trade_amounts = get_splitted_trade_amounts_from_trading_vec(single_trade_results)?;
+    // if there is a cow volume, we try to remove it
+    let updated_traded_amounts = get_trade_amounts_without_cow_volumes(&splitted_trade_amounts)?;
+
+    for (pair, entry_amounts) in &updated_traded_amounts {
+        tracing::debug!(
+            " After cow merge: trade on pair {:?} with values {:?}",
+            pair,
+            entry_amounts,
+        );
+    }
+
+    let (mut swap_results, updated_traded_amounts) =
+        get_swaps_for_left_over_amounts_from_amms(updated_traded_amounts, &mut amm_router);
+    let zero_ex_results =
+        get_swaps_for_left_over_amounts(updated_traded_amounts, context, deadline).await?;
+    swap_results.extend(
+        zero_ex_results
+            .into_iter()
+            .map(|(query, swap)| LiquidityTrade::from_zeroex(query, swap)),
+    );
+    let prices = candidate_clearing_prices(
+        &swap_results,
+        &splitted_trade_amounts,
+        tokens,
+        &context.chain,
+    )?;
+    check_limit_prices(&matched_orders, &prices.prices).context("the cows could not be netted")?;
+    Ok(Candidate {
+        matched_orders,
+        swap_results,
+        splitted_trade_amounts,
+    })
+}
+
+/// Settles every order on its own, against a liquidity order or the amms of the instance if
+/// they satisfy its limit price, and otherwise against zeroEx.
+async fn get_direct_candidate(
+    orders: Vec<(usize, OrderModel)>,
+    liquidity_orders: &[(usize, OrderModel)],
+    max_nr_liquidity_orders: usize,
+    mut amm_router: AmmRouter,
+    context: &SolverContext,
+    deadline: Instant,
+) -> Candidate {
+    let (liquidity_order_results, remaining_orders) = get_swaps_for_orders_from_liquidity_orders(
+        orders,
+        liquidity_orders,
+        &amm_router,
+        max_nr_liquidity_orders,
+    );
+    let (amm_results, remaining_orders) =
+        get_swaps_for_orders_from_amms(remaining_orders, &mut amm_router);
+    let zero_ex_results =
+        match get_swaps_for_orders_from_zeroex(remaining_orders.clone(), context, deadline).await {
+            Ok(zero_ex_results) => zero_ex_results,
             Err(err) => {
-                tracing::debug!(
-                    "Inserting a price failed due to {:?}, returning trivial solution",
-                    err
-                );
-                return Ok(SettledBatchAuctionModel::default());
+                tracing::debug!("Error while calling zeroEx api in fallback mode: {:?}", err);
+                // Orders settled by the amms don't depend on zeroEx.
+                Vec::new()
             }
+        };
+    // Partially fillable orders, which could not be settled completely, are settled
+    // as far as the amms allow it within their limit price.
+    let unsettled_orders = remaining_orders
+        .into_iter()
+        .filter(|(index, _)| !zero_ex_results.iter().any(|((i, _), _)| i == index))
+        .collect();
+    let partial_fill_results = get_partial_fills_from_amms(unsettled_orders, &mut amm_router);
+    let (matched_orders, swap_results) = liquidity_order_results
+        .into_iter()
+        .chain(amm_results)
+        .chain(partial_fill_results)
+        .chain(
+            zero_ex_results
+                .into_iter()
+                .map(|(order, (query, swap))| (order, LiquidityTrade::from_zeroex(query, swap))),
+        )
+        .unzip();
+    Candidate {
+        matched_orders,
+        swap_results,
+        splitted_trade_amounts: HashMap::new(),
+    }
+}
+
+/// The gas price of the instance, or the current gas price of the node if the instance
+/// doesn't contain one.
+async fn get_gas_price(
+    metadata: Option<MetadataModel>,
+    context: &SolverContext,
+    deadline: Instant,
+) -> Option<f64> {
+    if let Some(gas_price) = metadata.and_then(|metadata| metadata.gas_price) {
+        return Some(gas_price);
+    }
+    match timeout_at(deadline, context.web3.eth().gas_price()).await {
+        Ok(Ok(gas_price)) => big_rational_to_float(&gas_price.to_big_rational()),
+        Ok(Err(err)) => {
+            tracing::debug!("Could not get the gas price from the node: {:?}", err);
+            None
+        }
+        Err(_) => {
+            tracing::debug!("Deadline reached while fetching the gas price");
+            None
         }
+    }
+}
 
+/// Builds the settlement of a candidate with prices, interactions and executed orders.
+async fn build_settlement(
+    Candidate {
+        matched_orders,
+        swap_results,
+        splitted_trade_amounts,
+    }: Candidate,
+    mut tokens: BTreeMap<H160, TokenInfoModel>,
+    context: &SolverContext,
+    deadline: Instant,
+) -> SettledBatchAuctionModel {
+    // 4th step: Get all approvals via a batch requests for the different swap
+    let mut allowances = get_allowances_for_tokens_involved(&swap_results, context, deadline).await;
+
+    // 5th step: Build settlements with price and interactions
+    let mut solution = SettledBatchAuctionModel::default();
+    let prices = candidate_clearing_prices(
+        &swap_results,
+        &splitted_trade_amounts,
+        &tokens,
+        &context.chain,
+    );
+    // Trades on the same pair clear at their average rate, which may violate the limit price
+    // of an order whose own trade got a better rate than the others.
+    let prices = prices.and_then(|prices| {
+        check_limit_prices(&matched_orders, &prices.prices)?;
+        Ok(prices)
+    });
+    match prices {
+        Ok(prices) => {
+            solution.ref_token = prices.ref_token;
+            solution.prices = prices.prices;
+        }
+        Err(err) => {
+            tracing::debug!(
+                "Computing the clearing prices failed due to {:?}, returning trivial solution",
+                err
+            );
+            return SettledBatchAuctionModel::default();
+        }
+    }
+    let tradable_buffer_token_list = get_buffer_tradable_token_list();
+    // Amm routes are sequenced in the same order as the interactions are pushed.
+    let mut amm_sequence = 0u32;
+    for trade in &swap_results {
         let available_buffer = tokens
             .clone()
-            .get(&query.buy_token)
+            .get(&trade.buy_token)
             .unwrap_or(&TokenInfoModel::default())
             .internal_buffer
             .unwrap_or_else(U256::zero);
-        if swap.buy_amount < available_buffer
-            && swap_tokens_are_tradable_buffer_tokens(&query, &tradable_buffer_token_list)
+        if let TradeExecution::Ring(_) = &trade.execution {
+            // rings are settled within the settlement and need no interactions
+        } else if let TradeExecution::LiquidityOrder(index, executed_order) = &trade.execution {
+            // liquidity orders are settled within the settlement and need no interactions
+            solution.orders.insert(*index, executed_order.clone());
+        } else if let TradeExecution::Cow(cow_match) = &trade.execution {
+            // only the residual of a cow needs an interaction
+            match &cow_match.residual {
+                Some(Residual::Amm(route)) => {
+                    insert_amm_executions(&mut solution, route, amm_sequence);
+                    amm_sequence += 1;
+                }
+                Some(Residual::ZeroEx(swap)) => insert_zeroex_interactions(
+                    &mut solution,
+                    &mut allowances,
+                    cow_match.sell_token,
+                    swap,
+                    context,
+                ),
+                None => (),
+            }
+        } else if trade.buy_amount() < available_buffer
+            && swap_tokens_are_tradable_buffer_tokens(
+                trade,
+                &tradable_buffer_token_list,
+                context.chain.chain_id,
+            )
         {
             // trade only against internal buffer
-            if let Some(mut token_info) = tokens.get_mut(&query.buy_token) {
-                token_info.internal_buffer = available_buffer.checked_sub(swap.buy_amount);
+            if let Some(mut token_info) = tokens.get_mut(&trade.buy_token) {
+                token_info.internal_buffer = available_buffer.checked_sub(trade.buy_amount());
             }
-            if let Some(mut token_info) = tokens.get_mut(&query.sell_token) {
+            if let Some(mut token_info) = tokens.get_mut(&trade.sell_token) {
                 if let Some(buffer) = token_info.internal_buffer {
-                    token_info.internal_buffer = buffer.checked_add(swap.sell_amount);
+                    token_info.internal_buffer = buffer.checked_add(trade.sell_amount());
                 } else {
-                    token_info.internal_buffer = Some(swap.sell_amount);
+                    token_info.internal_buffer = Some(trade.sell_amount());
                 }
             }
-        } else {
+        } else if let TradeExecution::Amm(route) = &trade.execution {
+            // the driver encodes the interactions for the amms of the instance
+            insert_amm_executions(&mut solution, route, amm_sequence);
+            amm_sequence += 1;
+        } else if let TradeExecution::ZeroEx(swap) = &trade.execution {
             // use external trade
-            let spender = swap.allowance_target;
-            // Push allowance interaction data, if necessary
-            let allowance = allowances
-                .entry((query.sell_token, spender))
-                .or_insert_with(U256::zero);
-            if allowance.lt(&&mut swap.sell_amount) {
-                let token = ERC20::at(&web3, query.sell_token);
-                let method = token.approve(spender, swap.sell_amount);
-                let calldata = method.tx.data.expect("no calldata").0;
-                let interaction_item = InteractionData {
-                    target: query.sell_token,
-                    value: 0.into(),
-                    call_data: ethcontract::Bytes(calldata),
-                };
-                solution.interaction_data.push(interaction_item);
-            } else {
-                *allowance = allowance.checked_sub(swap.sell_amount).unwrap()
-            }
-            // put swap tx data into settled_batch_auction
-            let interaction_item = InteractionData {
-                target: swap.to,
-                value: swap.value,
-                call_data: ethcontract::Bytes(swap.data.0),
-            };
-            solution.interaction_data.push(interaction_item);
+            insert_zeroex_interactions(
+                &mut solution,
+                &mut allowances,
+                trade.sell_token,
+                swap,
+                context,
+            );
         }
-
-        // Sort swap_results in such a way that the next pop contains a token already processed in the clearing prices, if there exists one.
-        swap_results.sort_by(|a, b| {
-            one_token_is_already_in_settlement(&solution, a)
-                .cmp(&one_token_is_already_in_settlement(&solution, b))
-        })
     }
 
     // 6th step: Insert traded orders into settlement
@@ -225,8 +584,70 @@ pub async fn solve(
             },
         );
     }
-    tracing::info!("Found solution: {:?}", solution);
-    Ok(solution)
+    solution
+}
+
+/// Pushes the interaction of a zeroEx swap, preceded by an approval of its spender if the
+/// allowance of the settlement doesn't cover the swap.
+fn insert_zeroex_interactions(
+    solution: &mut SettledBatchAuctionModel,
+    allowances: &mut HashMap<(Address, Address), U256>,
+    sell_token: H160,
+    swap: &SwapResponse,
+    context: &SolverContext,
+) {
+    let spender = swap.allowance_target;
+    // Push allowance interaction data, if necessary
+    let allowance = allowances
+        .entry((sell_token, spender))
+        .or_insert_with(U256::zero);
+    if *allowance < swap.sell_amount {
+        let token = ERC20::at(&context.web3, sell_token);
+        let method = token.approve(spender, swap.sell_amount);
+        let calldata = method.tx.data.expect("no calldata").0;
+        let interaction_item = InteractionData {
+            target: sell_token,
+            value: 0.into(),
+            call_data: ethcontract::Bytes(calldata),
+        };
+        solution.interaction_data.push(interaction_item);
+    } else {
+        *allowance = allowance.checked_sub(swap.sell_amount).unwrap()
+    }
+    // put swap tx data into settled_batch_auction
+    let interaction_item = InteractionData {
+        target: swap.to,
+        value: swap.value,
+        call_data: ethcontract::Bytes(swap.data.0.clone()),
+    };
+    solution.interaction_data.push(interaction_item);
+}
+
+/// Reduces the order to the amounts available for trading, after deducting its fee.
+///
+/// The driver charges the fee in addition to the executed sell amount, so the sell amount of
+/// the order is reduced by the fee, while the buy amount it expects stays the same. Fees in
+/// any other token than the sell token are not supported.
+fn order_net_of_fee(order: OrderModel) -> Result<OrderModel> {
+    if order.fee.amount.is_zero() {
+        return Ok(order);
+    }
+    if order.fee.token != order.sell_token {
+        return Err(anyhow!(
+            "fee in token {:?} instead of the sell token {:?}",
+            order.fee.token,
+            order.sell_token
+        ));
+    }
+    let sell_amount = order
+        .sell_amount
+        .checked_sub(order.fee.amount)
+        .filter(|sell_amount| !sell_amount.is_zero())
+        .ok_or_else(|| anyhow!("fee exceeds the sell amount"))?;
+    Ok(OrderModel {
+        sell_amount,
+        ..order
+    })
 }
 
 fn swap_respects_limit_price(swap: &SwapResponse, order: &OrderModel) -> bool {
@@ -236,57 +657,58 @@ fn swap_respects_limit_price(swap: &SwapResponse, order: &OrderModel) -> bool {
     }
 }
 
-fn is_market_order(tokens: &BTreeMap<H160, TokenInfoModel>, order: OrderModel) -> Result<bool> {
-    // works currently only for sell orders
-    let sell_token_price = tokens
-        .get(&order.sell_token)
-        .ok_or_else(|| anyhow!("sell token price not available"))?
-        .external_price
-        .ok_or_else(|| anyhow!("sell token price not available"))?;
-    let buy_token_price = tokens
-        .get(&order.buy_token)
-        .ok_or_else(|| anyhow!("buy token price not available"))?
-        .external_price
-        .ok_or_else(|| anyhow!("buy token price not available"))?;
-
-    Ok((order.is_sell_order
-        && (order.sell_amount.as_u128() as f64) * (sell_token_price)
-            > (order.buy_amount.as_u128() as f64) * buy_token_price * 0.995f64)
-        || (!order.is_sell_order
-            && (order.buy_amount.as_u128() as f64) * (buy_token_price)
-                < (order.sell_amount.as_u128() as f64) * sell_token_price * 1.005f64))
-}
-
 async fn get_allowances_for_tokens_involved(
-    swap_results: &[(SwapQuery, SwapResponse)],
+    swap_results: &[LiquidityTrade],
+    context: &SolverContext,
+    deadline: Instant,
 ) -> HashMap<(Address, Address), U256> {
-    let http = Http::new("https://staging-openethereum.mainnet.gnosisdev.com").unwrap();
-    let web3 = Web3::new(http);
-    let settlement_contract_address: H160 =
-        "9008d19f58aabd9ed0d60971565aa8510560ab41".parse().unwrap();
+    // Only zeroEx trades, including the residuals of cows, need approvals. The driver takes
+    // care of the amms of the instance.
+    let swap_results: Vec<(H160, &SwapResponse)> = swap_results
+        .iter()
+        .filter_map(|trade| match &trade.execution {
+            TradeExecution::ZeroEx(swap) => Some((trade.sell_token, swap)),
+            TradeExecution::Cow(CowMatch {
+                residual: Some(Residual::ZeroEx(swap)),
+                ..
+            }) => Some((trade.sell_token, swap)),
+            TradeExecution::Amm(_)
+            | TradeExecution::LiquidityOrder(..)
+            | TradeExecution::Cow(_)
+            | TradeExecution::Ring(_) => None,
+        })
+        .collect();
+    let web3 = &context.web3;
     let mut batch = CallBatch::new(web3.transport());
     let mut calls = Vec::new();
-    for (query, swap) in swap_results {
-        let token = ERC20::at(&web3, query.sell_token);
+    for (sell_token, swap) in &swap_results {
+        let token = ERC20::at(web3, *sell_token);
         calls.push(
             token
-                .allowance(settlement_contract_address, swap.allowance_target)
+                .allowance(context.chain.settlement_contract, swap.allowance_target)
                 .batch_call(&mut batch),
         )
     }
-    batch.execute_all(usize::MAX).await;
     let mut allowances: HashMap<(Address, Address), U256> = HashMap::new();
+    if timeout_at(deadline, batch.execute_all(usize::MAX))
+        .await
+        .is_err()
+    {
+        // Without known allowances, approvals are added for every swap, which is still valid.
+        tracing::debug!("Deadline reached while fetching allowances");
+        return allowances;
+    }
     for (id, call) in calls.into_iter().enumerate() {
         let call_result = call.await.unwrap_or_else(|_| U256::zero());
-        if let Some((query, swap)) = swap_results.get(id) {
+        if let Some((sell_token, swap)) = swap_results.get(id) {
             tracing::debug!(
-                "Call {} returned {} for query:{:?} and swap:{:?}",
+                "Call {} returned {} for sell token:{:?} and swap:{:?}",
                 id,
                 call_result,
-                query,
+                sell_token,
                 swap
             );
-            allowances.insert((query.sell_token, swap.allowance_target), call_result);
+            allowances.insert((*sell_token, swap.allowance_target), call_result);
         } else {
             tracing::debug!("Call {} returned {}", id, call_result);
         }
@@ -294,87 +716,403 @@ async fn get_allowances_for_tokens_involved(
     allowances
 }
 
+/// Settles orders against the liquidity orders of the instance, whenever a liquidity order
+/// gives the order at least as much as the amms would. Liquidity orders are executed at their
+/// limit price, each of them at most once and at most `max_nr_liquidity_orders` in total.
+fn get_swaps_for_orders_from_liquidity_orders(
+    orders: Vec<(usize, OrderModel)>,
+    liquidity_orders: &[(usize, OrderModel)],
+    amm_router: &AmmRouter,
+    max_nr_liquidity_orders: usize,
+) -> (Vec<OrderTrade>, Vec<(usize, OrderModel)>) {
+    let mut liquidity_order_results = Vec::new();
+    let mut remaining_orders = Vec::new();
+    let mut used_liquidity_orders = HashSet::new();
+    for (index, order) in orders {
+        if used_liquidity_orders.len() >= max_nr_liquidity_orders {
+            remaining_orders.push((index, order));
+            continue;
+        }
+        let executed_amount = match order.is_sell_order {
+            true => order.sell_amount,
+            false => order.buy_amount,
+        };
+        let amm_route = quote_order_from_amms(&order, amm_router, executed_amount);
+        // The best fill for the order is the one paying it the most for sell orders, and the
+        // one costing it the least for buy orders.
+        let best_fill = liquidity_orders
+            .iter()
+            .filter(|(liquidity_index, _)| !used_liquidity_orders.contains(liquidity_index))
+            .filter_map(|(liquidity_index, liquidity_order)| {
+                fill_against_liquidity_order(&order, liquidity_order)
+                    .map(|executed_order| (*liquidity_index, executed_order))
+            })
+            .filter(
+                |(_, executed_order)| match (&amm_route, order.is_sell_order) {
+                    (None, _) => true,
+                    (Some(route), true) => executed_order.exec_sell_amount >= route.buy_amount(),
+                    (Some(route), false) => executed_order.exec_buy_amount <= route.sell_amount(),
+                },
+            )
+            .max_by(|(_, a), (_, b)| match order.is_sell_order {
+                true => a.exec_sell_amount.cmp(&b.exec_sell_amount),
+                false => b.exec_buy_amount.cmp(&a.exec_buy_amount),
+            });
+        match best_fill {
+            Some((liquidity_index, executed_order)) => {
+                tracing::debug!(
+                    "Settling order {} against liquidity order {}",
+                    index,
+                    liquidity_index
+                );
+                used_liquidity_orders.insert(liquidity_index);
+                let trade =
+                    LiquidityTrade::from_liquidity_order(&order, liquidity_index, executed_order);
+                liquidity_order_results.push(((index, order), trade));
+            }
+            None => remaining_orders.push((index, order)),
+        }
+    }
+    (liquidity_order_results, remaining_orders)
+}
+
+/// The executed amounts of a liquidity order, which fully settles the order at the limit price
+/// of the liquidity order, if that respects the limit price of the order as well.
+fn fill_against_liquidity_order(
+    order: &OrderModel,
+    liquidity_order: &OrderModel,
+) -> Option<ExecutedOrderModel> {
+    if liquidity_order.sell_token != order.buy_token
+        || liquidity_order.buy_token != order.sell_token
+        || liquidity_order.sell_amount.is_zero()
+        || liquidity_order.buy_amount.is_zero()
+    {
+        return None;
+    }
+    // Rounding is in favour of the liquidity order.
+    let (exec_buy_amount, exec_sell_amount) = match order.is_sell_order {
+        true => {
+            let exec_sell_amount = big_int_to_u256(
+                &(order.sell_amount.to_big_int() * liquidity_order.sell_amount.to_big_int())
+                    .div_floor(&liquidity_order.buy_amount.to_big_int()),
+            )
+            .ok()?;
+            if exec_sell_amount < order.buy_amount {
+                return None;
+            }
+            (order.sell_amount, exec_sell_amount)
+        }
+        false => {
+            let exec_buy_amount = big_int_to_u256(
+                &(order.buy_amount.to_big_int() * liquidity_order.buy_amount.to_big_int())
+                    .div_ceil(&liquidity_order.sell_amount.to_big_int()),
+            )
+            .ok()?;
+            if exec_buy_amount > order.sell_amount {
+                return None;
+            }
+            (exec_buy_amount, order.buy_amount)
+        }
+    };
+    if exec_sell_amount > liquidity_order.sell_amount
+        || exec_buy_amount > liquidity_order.buy_amount
+    {
+        return None;
+    }
+    let is_fully_executed = match liquidity_order.is_sell_order {
+        true => exec_sell_amount == liquidity_order.sell_amount,
+        false => exec_buy_amount == liquidity_order.buy_amount,
+    };
+    if !liquidity_order.allow_partial_fill && !is_fully_executed {
+        return None;
+    }
+    Some(ExecutedOrderModel {
+        exec_sell_amount,
+        exec_buy_amount,
+    })
+}
+
+fn get_swaps_for_orders_from_amms(
+    orders: Vec<(usize, OrderModel)>,
+    amm_router: &mut AmmRouter,
+) -> (Vec<OrderTrade>, Vec<(usize, OrderModel)>) {
+    let mut amm_results = Vec::new();
+    let mut remaining_orders = Vec::new();
+    for (index, order) in orders {
+        if order.is_liquidity_order {
+            remaining_orders.push((index, order));
+            continue;
+        }
+        let executed_amount = match order.is_sell_order {
+            true => order.sell_amount,
+            false => order.buy_amount,
+        };
+        match quote_order_from_amms(&order, amm_router, executed_amount) {
+            Some(route) => {
+                amm_router.execute(&route);
+                let trade = LiquidityTrade::from_amm(route);
+                amm_results.push(((index, order), trade));
+            }
+            None => remaining_orders.push((index, order)),
+        }
+    }
+    (amm_results, remaining_orders)
+}
+
+/// Number of bisection steps used to find the executable part of a partially fillable order.
+const PARTIAL_FILL_BISECTION_STEPS: usize = 32;
+
+fn get_partial_fills_from_amms(
+    orders: Vec<(usize, OrderModel)>,
+    amm_router: &mut AmmRouter,
+) -> Vec<OrderTrade> {
+    let mut amm_results = Vec::new();
+    for (index, order) in orders {
+        if !order.allow_partial_fill || order.is_liquidity_order {
+            continue;
+        }
+        // Executing more of an order only makes its price worse, hence the largest
+        // executable amount can be found with a bisection.
+        let full_amount = match order.is_sell_order {
+            true => order.sell_amount,
+            false => order.buy_amount,
+        };
+        let (mut lower, mut upper) = (U256::zero(), full_amount);
+        let mut best_fill = None;
+        for _ in 0..PARTIAL_FILL_BISECTION_STEPS {
+            if upper - lower <= U256::one() {
+                break;
+            }
+            let amount = lower + (upper - lower) / 2;
+            match quote_order_from_amms(&order, amm_router, amount) {
+                Some(route) => {
+                    lower = amount;
+                    best_fill = Some((amount, route));
+                }
+                None => upper = amount,
+            }
+        }
+        let (executed_amount, route) = match best_fill {
+            Some(best_fill) => best_fill,
+            None => continue,
+        };
+        let partially_filled_order = match partially_filled_order(&order, executed_amount) {
+            Some(partially_filled_order) => partially_filled_order,
+            None => continue,
+        };
+        tracing::debug!(
+            "Partially filling order {} with {} of {}",
+            index,
+            executed_amount,
+            full_amount
+        );
+        amm_router.execute(&route);
+        amm_results.push((
+            (index, partially_filled_order),
+            LiquidityTrade::from_amm(route),
+        ));
+    }
+    amm_results
+}
+
+/// Quotes a route for the given executed amount of the order, which is the sell amount for
+/// sell orders and the buy amount for buy orders, if it respects the limit price of the order.
+fn quote_order_from_amms(
+    order: &OrderModel,
+    amm_router: &AmmRouter,
+    executed_amount: U256,
+) -> Option<AmmRoute> {
+    match order.is_sell_order {
+        true => amm_router
+            .quote_sell(order.sell_token, order.buy_token, executed_amount)
+            .filter(|route| {
+                route.buy_amount().full_mul(order.sell_amount)
+                    >= order.buy_amount.full_mul(executed_amount)
+            }),
+        false => amm_router
+            .quote_buy(order.sell_token, order.buy_token, executed_amount)
+            .filter(|route| {
+                route.sell_amount().full_mul(order.buy_amount)
+                    <= order.sell_amount.full_mul(executed_amount)
+            }),
+    }
+}
+
+/// The order reduced to the executed amount, with the other amount scaled according to its
+/// limit price.
+fn partially_filled_order(order: &OrderModel, executed_amount: U256) -> Option<OrderModel> {
+    if executed_amount.is_zero() {
+        return None;
+    }
+    let mut partially_filled_order = order.clone();
+    match order.is_sell_order {
+        true => {
+            partially_filled_order.sell_amount = executed_amount;
+            partially_filled_order.buy_amount = big_int_to_u256(
+                &(order.buy_amount.to_big_int() * executed_amount.to_big_int())
+                    .div_ceil(&order.sell_amount.to_big_int()),
+            )
+            .ok()?;
+        }
+        false => {
+            partially_filled_order.buy_amount = executed_amount;
+            partially_filled_order.sell_amount = big_int_to_u256(
+                &(order.sell_amount.to_big_int() * executed_amount.to_big_int())
+                    .div_floor(&order.buy_amount.to_big_int()),
+            )
+            .ok()?;
+        }
+    }
+    Some(partially_filled_order)
+}
+
 async fn get_swaps_for_orders_from_zeroex(
     orders: Vec<(usize, OrderModel)>,
-    api_key: Option<String>,
+    context: &SolverContext,
+    deadline: Instant,
 ) -> Result<Vec<((usize, OrderModel), (SwapQuery, SwapResponse))>> {
+    if orders.is_empty() {
+        return Ok(Vec::new());
+    }
+    let zeroex_solver = context
+        .zeroex
+        .as_ref()
+        .ok_or_else(|| anyhow!("0x is not available on chain {}", context.chain.chain_id))?;
     let zeroex_futures = orders
         .into_iter()
         .filter(|(_, x)| !x.is_liquidity_order)
-        .map(|(index, order)| {
-            let cloned_api_key = api_key.clone();
-            async move {
-                let client = reqwest::ClientBuilder::new()
-                    .timeout(Duration::new(5, 0))
-                    .user_agent("gp-v2-services/2.0.0")
-                    .build()
-                    .unwrap();
-                let zeroex_solver =
-                    ZeroExSolver::new(1u64, cloned_api_key, client.clone()).unwrap();
-
-                let query = match order.is_sell_order {
-                    true => SwapQuery {
-                        sell_token: order.sell_token,
-                        buy_token: order.buy_token,
-                        sell_amount: Some(order.sell_amount),
-                        buy_amount: None,
-                        slippage_percentage: Slippage::number_from_basis_points(10u16).unwrap(),
-                        skip_validation: Some(true),
-                    },
-                    false => SwapQuery {
-                        sell_token: order.sell_token,
-                        buy_token: order.buy_token,
-                        sell_amount: None,
-                        buy_amount: Some(order.buy_amount),
-                        slippage_percentage: Slippage::number_from_basis_points(10u16).unwrap(),
-                        skip_validation: Some(true),
-                    },
-                };
-                (
-                    index,
-                    order,
-                    query.clone(),
-                    zeroex_solver.client.get_swap(query).await,
-                )
+        .map(|(index, order)| async move {
+            let query = match order.is_sell_order {
+                true => SwapQuery {
+                    sell_token: order.sell_token,
+                    buy_token: order.buy_token,
+                    sell_amount: Some(order.sell_amount),
+                    buy_amount: None,
+                    slippage_percentage: Slippage::number_from_basis_points(10u16).unwrap(),
+                    skip_validation: Some(true),
+                },
+                false => SwapQuery {
+                    sell_token: order.sell_token,
+                    buy_token: order.buy_token,
+                    sell_amount: None,
+                    buy_amount: Some(order.buy_amount),
+                    slippage_percentage: Slippage::number_from_basis_points(10u16).unwrap(),
+                    skip_validation: Some(true),
+                },
+            };
+            (
+                index,
+                order,
+                query.clone(),
+                zeroex_solver.client.get_swap(query).await,
+            )
+        });
+    // Orders whose quote did not arrive in time are simply not part of the solution.
+    let swap_results = collect_until_deadline(zeroex_futures, deadline).await;
+    let mut swaps = Vec::new();
+    for (index, order, query, swap) in swap_results {
+        let swap = swap.map_err(AggregatorError::from).and_then(|swap| {
+            match swap_respects_limit_price(&swap, &order) {
+                true => Ok(swap),
+                false => Err(AggregatorError::LimitPriceViolated),
             }
         });
-    let swap_results = join_all(zeroex_futures).await;
-    swap_results
-        .iter()
-        .map(|(index, order, query, swap)| match swap {
-            Ok(swap) => {
-                if !swap_respects_limit_price(swap, order) {
-                    return Err(anyhow!("swap price not good enough"));
+        match swap {
+            Ok(swap) => swaps.push(((index, order), (query, swap))),
+            Err(err) => match err.policy() {
+                FailurePolicy::DropOrder => {
+                    tracing::debug!("Order {} is not settled via zeroEx: {}", index, err)
                 }
-                Ok(((*index, order.clone()), (query.clone(), swap.clone())))
+                FailurePolicy::AbortBatch => return Err(err.into()),
+            },
+        }
+    }
+    Ok(swaps)
+}
+
+/// Quotes the residual of a cow on the amms of the instance and on zeroEx, and takes the
+/// trade paying more. The amms are preferred on equal quotes, as they need no approvals.
+async fn quote_residual(
+    amm_router: &AmmRouter,
+    sell_token: H160,
+    buy_token: H160,
+    sell_amount: U256,
+    context: &SolverContext,
+    deadline: Instant,
+) -> Option<Residual> {
+    let amm_route = amm_router.quote_sell(sell_token, buy_token, sell_amount);
+    let left_over_amount = TradeAmount {
+        sell_amount,
+        buy_amount: amm_route
+            .as_ref()
+            .map(|route| route.buy_amount())
+            .unwrap_or_default(),
+    };
+    let zeroex_swap = match get_swaps_for_left_over_amounts(
+        HashMap::from([((sell_token, buy_token), left_over_amount)]),
+        context,
+        deadline,
+    )
+    .await
+    {
+        Ok(swaps) => swaps.into_iter().next(),
+        Err(err) => {
+            tracing::debug!("Could not trade the residual of a cow on zeroEx: {:?}", err);
+            None
+        }
+    };
+    match (amm_route, zeroex_swap) {
+        (Some(route), Some((_, swap))) if route.buy_amount() >= swap.buy_amount => {
+            Some(Residual::Amm(route))
+        }
+        (_, Some((_, swap))) => Some(Residual::ZeroEx(swap)),
+        (route, None) => route.map(Residual::Amm),
+    }
+}
+
+fn get_swaps_for_left_over_amounts_from_amms(
+    updated_traded_amounts: HashMap<(H160, H160), TradeAmount>,
+    amm_router: &mut AmmRouter,
+) -> (Vec<LiquidityTrade>, HashMap<(H160, H160), TradeAmount>) {
+    let mut amm_results = Vec::new();
+    let mut remaining_amounts = HashMap::new();
+    for ((src_token, dest_token), trade_amount) in updated_traded_amounts {
+        let route = amm_router
+            .quote_sell(src_token, dest_token, trade_amount.sell_amount)
+            .filter(|route| {
+                route.sell_amount().full_mul(trade_amount.buy_amount)
+                    <= trade_amount.sell_amount.full_mul(route.buy_amount())
+            });
+        match route {
+            Some(route) => {
+                amm_router.execute(&route);
+                amm_results.push(LiquidityTrade::from_amm(route));
             }
-            Err(err) => Err(anyhow!("error from zeroex:{:?}", err)),
-        })
-        .filter(|x| {
-            x.is_ok()
-                || (format!("{:?}", x).contains("error from zeroex")
-                    && !format!("{:?}", x).contains("TimedOut"))
-        })
-        .collect()
+            None => {
+                remaining_amounts.insert((src_token, dest_token), trade_amount);
+            }
+        }
+    }
+    (amm_results, remaining_amounts)
 }
 
 async fn get_swaps_for_left_over_amounts(
     updated_traded_amounts: HashMap<(H160, H160), TradeAmount>,
-    api_key: Option<String>,
+    context: &SolverContext,
+    deadline: Instant,
 ) -> Result<Vec<(SwapQuery, SwapResponse)>> {
-    let zeroex_futures = updated_traded_amounts
-        .into_iter()
-        .map(|(pair, trade_amount)| {
-            let cloned_api_key = api_key.clone();
-            async move {
-                let client = reqwest::ClientBuilder::new()
-                    .timeout(Duration::new(3, 0))
-                    .user_agent("gp-v2-services/2.0.0")
-                    .build()
-                    .unwrap();
-                let zeroex_solver =
-                    ZeroExSolver::new(1u64, cloned_api_key, client.clone()).unwrap();
-
+    if updated_traded_amounts.is_empty() {
+        return Ok(Vec::new());
+    }
+    let zeroex_solver = context
+        .zeroex
+        .as_ref()
+        .ok_or_else(|| anyhow!("0x is not available on chain {}", context.chain.chain_id))?;
+    let number_of_left_over_trades = updated_traded_amounts.len();
+    let zeroex_futures =
+        updated_traded_amounts
+            .into_iter()
+            .map(|(pair, trade_amount)| async move {
                 let (src_token, dest_token) = pair;
                 let query = SwapQuery {
                     sell_token: src_token,
@@ -389,89 +1127,211 @@ async fn get_swaps_for_left_over_amounts(
                     query.clone(),
                     zeroex_solver.client.get_swap(query).await,
                 )
-            }
-        });
-    let swap_results = join_all(zeroex_futures).await;
+            });
+    let swap_results = collect_until_deadline(zeroex_futures, deadline).await;
+    // Every left-over amount needs to be traded for the cow settlement to be valid.
+    if swap_results.len() < number_of_left_over_trades {
+        return Err(anyhow!(
+            "deadline reached before all left-over amounts could be traded"
+        ));
+    }
+    // Unlike for orders, no failure can be dropped, as every left-over amount is needed.
     swap_results
-        .iter()
-        .map(|(trade_amount, query, swap)| match swap {
-            Ok(swap) => {
-                if trade_amount.must_satisfy_limit_price
-                    && swap
-                        .sell_amount
-                        .checked_mul(trade_amount.buy_amount)
-                        .gt(&trade_amount.sell_amount.checked_mul(swap.buy_amount))
-                {
-                    return Err(anyhow!("swap price not good enough"));
-                }
-                Ok((query.clone(), swap.clone()))
+        .into_iter()
+        .map(|(trade_amount, query, swap)| {
+            let swap = swap.map_err(AggregatorError::from)?;
+            if swap.sell_amount.full_mul(trade_amount.buy_amount)
+                > trade_amount.sell_amount.full_mul(swap.buy_amount)
+            {
+                return Err(AggregatorError::LimitPriceViolated.into());
             }
-            Err(err) => Err(anyhow!("error from zeroex:{:?}", err)),
+            Ok((query, swap))
         })
-        .filter(|x| x.is_ok() || format!("{:?}", x).contains("error from zeroex"))
         .collect()
 }
 
+/// The orders whose paraswap route could be decomposed into sub trades, and the reasons why
+/// the other orders could not.
+#[derive(Debug, Default)]
+struct ParaswapSubTrades {
+    matched_orders: Vec<(usize, OrderModel)>,
+    sub_trades: Vec<SubTrade>,
+    failed_orders: Vec<(usize, anyhow::Error)>,
+}
+
 async fn get_matchable_orders_and_subtrades(
     orders: Vec<(usize, OrderModel)>,
     tokens: BTreeMap<H160, TokenInfoModel>,
-) -> (Vec<(usize, OrderModel)>, Vec<SubTrade>) {
-    let mut paraswap_futures = Vec::new();
-    let client = reqwest::ClientBuilder::new()
-        .timeout(Duration::new(3, 0))
-        .user_agent("gp-v2-services/2.0.0")
-        .build()
-        .unwrap();
-
-    for (i, order) in orders.iter() {
-        let paraswap_solver =
-            ParaswapSolver::new(vec![String::from("ParaSwapPool4")], client.clone());
+    context: &SolverContext,
+    deadline: Instant,
+) -> ParaswapSubTrades {
+    let api = match &context.paraswap {
+        Some(api) => api,
+        None => return ParaswapSubTrades::default(),
+    };
+    let chain = &context.chain;
+    collect_sub_trades_per_order(orders, deadline, |order| {
+        let paraswap_solver = ParaswapSolver::new(
+            chain.chain_id,
+            api.clone(),
+            vec![String::from("ParaSwapPool4")],
+        );
+        get_paraswap_sub_trades_from_order(paraswap_solver, order, tokens.clone(), chain)
+    })
+    .await
+}
 
-        paraswap_futures.push(get_paraswap_sub_trades_from_order(
-            *i,
-            paraswap_solver,
-            order,
-            tokens.clone(),
-        ));
+/// Queries the sub trades of all orders concurrently. An order whose query fails, or which
+/// could not be queried before the deadline, is left out with its reason, while the other
+/// orders are still matched.
+async fn collect_sub_trades_per_order<F, Fut>(
+    orders: Vec<(usize, OrderModel)>,
+    deadline: Instant,
+    query: F,
+) -> ParaswapSubTrades
+where
+    F: Fn(OrderModel) -> Fut,
+    Fut: Future<Output = Result<Vec<SubTrade>>>,
+{
+    let queries = orders.into_iter().map(|(index, order)| {
+        let query = timeout_at(deadline, query(order.clone()));
+        async move { (index, order, query.await) }
+    });
+    let mut result = ParaswapSubTrades::default();
+    for (index, order, sub_trades) in futures::future::join_all(queries).await {
+        match sub_trades {
+            Ok(Ok(sub_trades)) => {
+                result.matched_orders.push((index, order));
+                result.sub_trades.extend(sub_trades);
+            }
+            Ok(Err(err)) => result.failed_orders.push((index, err)),
+            Err(_) => result
+                .failed_orders
+                .push((index, anyhow!("deadline reached while querying paraswap"))),
+        }
     }
-    type OrdersAndSubTrades = (Vec<(usize, OrderModel)>, Vec<SubTrade>);
-    // In the following, we use the sequential evaluation, as otherwise paraswap will return errors.
-    // let awaited_paraswap_futures: Result<OrdersAndSubTradesVector, anyhow::Error> =
-    //     join_all(paraswap_futures).await.into_iter().collect();
-    let mut awaited_paraswap_futures: Vec<Result<OrdersAndSubTrades>> = Vec::new();
-    for future in paraswap_futures {
-        awaited_paraswap_futures.push(future.await);
-    }
-    let awaited_paraswap_futures: Result<Vec<OrdersAndSubTrades>, anyhow::Error> =
-        awaited_paraswap_futures.into_iter().collect();
-    type MatchedOrderBracket = (Vec<Vec<(usize, OrderModel)>>, Vec<Vec<SubTrade>>);
-    let (matched_orders, single_trade_results): MatchedOrderBracket;
-    if let Ok(paraswap_futures_results) = awaited_paraswap_futures {
-        let paraswap_future_results_unzipped = paraswap_futures_results.into_iter().unzip();
-        matched_orders = paraswap_future_results_unzipped.0;
-        single_trade_results = paraswap_future_results_unzipped.1;
-    } else {
-        return (Vec::new(), Vec::new());
+    for (index, err) in &result.failed_orders {
+        tracing::debug!("Order {} is not matched via paraswap: {:?}", index, err);
     }
-    let matched_orders: Vec<(usize, OrderModel)> = matched_orders.into_iter().flatten().collect();
-
-    let single_trade_results: Vec<SubTrade> = single_trade_results.into_iter().flatten().collect();
-    (matched_orders, single_trade_results)
+    result
 }
 
 fn swap_tokens_are_tradable_buffer_tokens(
-    query: &SwapQuery,
+    trade: &LiquidityTrade,
     tradable_buffer_token_list: &BufferTradingTokenList,
+    chain_id: u64,
 ) -> bool {
     tradable_buffer_token_list.tokens.contains(&Token {
-        address: query.sell_token,
-        chain_id: 1u64,
+        address: trade.sell_token,
+        chain_id,
     }) && tradable_buffer_token_list.tokens.contains(&Token {
-        address: query.buy_token,
-        chain_id: 1u64,
+        address: trade.buy_token,
+        chain_id,
     })
 }
 
+/// How a trade of the settlement is executed against external liquidity.
+#[derive(Clone, Debug)]
+pub enum TradeExecution {
+    ZeroEx(SwapResponse),
+    Amm(AmmRoute),
+    /// A liquidity order of the instance as counterparty, together with its executed amounts.
+    LiquidityOrder(usize, ExecutedOrderModel),
+    /// Opposite orders of the instance as counterparties, and the trade of their residual.
+    Cow(CowMatch),
+    /// Orders of the instance trading in a ring, which only need clearing prices.
+    Ring(RingMatch),
+}
+
+/// An order together with the trade settling it.
+type OrderTrade = ((usize, OrderModel), LiquidityTrade);
+
+#[derive(Clone, Debug)]
+pub struct LiquidityTrade {
+    pub sell_token: H160,
+    pub buy_token: H160,
+    pub execution: TradeExecution,
+}
+
+impl LiquidityTrade {
+    fn from_zeroex(query: SwapQuery, swap: SwapResponse) -> Self {
+        Self {
+            sell_token: query.sell_token,
+            buy_token: query.buy_token,
+            execution: TradeExecution::ZeroEx(swap),
+        }
+    }
+
+    fn from_amm(route: AmmRoute) -> Self {
+        Self {
+            sell_token: route
+                .swaps
+                .first()
+                .map(|swap| swap.sell_token)
+                .unwrap_or_default(),
+            buy_token: route
+                .swaps
+                .last()
+                .map(|swap| swap.buy_token)
+                .unwrap_or_default(),
+            execution: TradeExecution::Amm(route),
+        }
+    }
+
+    /// The trade of an order against a liquidity order, which sells the buy token of the
+    /// order for its sell token.
+    fn from_liquidity_order(
+        order: &OrderModel,
+        index: usize,
+        executed_liquidity_order: ExecutedOrderModel,
+    ) -> Self {
+        Self {
+            sell_token: order.sell_token,
+            buy_token: order.buy_token,
+            execution: TradeExecution::LiquidityOrder(index, executed_liquidity_order),
+        }
+    }
+
+    fn from_cow_match(cow_match: CowMatch) -> Self {
+        Self {
+            sell_token: cow_match.sell_token,
+            buy_token: cow_match.buy_token,
+            execution: TradeExecution::Cow(cow_match),
+        }
+    }
+
+    /// The trade of the first order of a ring. The other orders of the ring are settled by
+    /// the same trade.
+    fn from_ring_match(ring_match: RingMatch) -> Self {
+        let (_, first_order) = &ring_match.orders[0];
+        Self {
+            sell_token: first_order.sell_token,
+            buy_token: first_order.buy_token,
+            execution: TradeExecution::Ring(ring_match),
+        }
+    }
+
+    pub fn sell_amount(&self) -> U256 {
+        match &self.execution {
+            TradeExecution::ZeroEx(swap) => swap.sell_amount,
+            TradeExecution::Amm(route) => route.sell_amount(),
+            TradeExecution::LiquidityOrder(_, executed_order) => executed_order.exec_buy_amount,
+            TradeExecution::Cow(cow_match) => cow_match.sell_amount(),
+            TradeExecution::Ring(ring_match) => ring_match.orders[0].1.sell_amount,
+        }
+    }
+
+    pub fn buy_amount(&self) -> U256 {
+        match &self.execution {
+            TradeExecution::ZeroEx(swap) => swap.buy_amount,
+            TradeExecution::Amm(route) => route.buy_amount(),
+            TradeExecution::LiquidityOrder(_, executed_order) => executed_order.exec_sell_amount,
+            TradeExecution::Cow(cow_match) => cow_match.buy_amount(),
+            TradeExecution::Ring(ring_match) => ring_match.orders[0].1.buy_amount,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct SubTrade {
     pub src_token: H160,
@@ -480,44 +1340,64 @@ struct SubTrade {
     pub dest_amount: U256,
 }
 async fn get_paraswap_sub_trades_from_order(
-    index: usize,
     paraswap_solver: ParaswapSolver,
-    order: &OrderModel,
+    order: OrderModel,
     tokens: BTreeMap<primitive_types::H160, TokenInfoModel>,
-) -> Result<(Vec<(usize, OrderModel)>, Vec<SubTrade>)> {
-    // get tokeninfo from ordermodel
-    let (price_response, _amount) = match paraswap_solver
-        .get_full_price_info_for_order(order, tokens)
+    chain: &ChainConfig,
+) -> Result<Vec<SubTrade>> {
+    let (price_response, _amount) = paraswap_solver
+        .get_full_price_info_for_order(&order, tokens)
         .await
-    {
-        Ok(response) => response,
-        Err(err) => {
-            tracing::debug!(
-                "Could not get price for order {:?} with error: {:?}",
-                order,
-                err
-            );
-            return Err(anyhow!("price estimation failed"));
-        }
-    };
-    let mut sub_trades = Vec::new();
-    let mut matched_orders = Vec::new();
-    if satisfies_limit_price_with_buffer(&price_response, order) {
-        matched_orders.push((index, order.clone()));
-        for swap in &price_response.price_route.best_route.get(0).unwrap().swaps {
-            for trade in &swap.swap_exchanges {
-                let src_token = over_write_eth_with_weth_token(swap.src_token);
-                let dest_token = over_write_eth_with_weth_token(swap.dest_token);
-                sub_trades.push(SubTrade {
-                    src_token,
-                    dest_token,
-                    src_amount: trade.src_amount,
-                    dest_amount: trade.dest_amount,
-                });
-            }
-        }
+        .map_err(|err| match err.downcast::<ParaswapResponseError>() {
+            Ok(err) => anyhow::Error::from(AggregatorError::from(err)),
+            Err(err) => err,
+        })
+        .context("price estimation failed")?;
+    if !satisfies_limit_price_with_buffer(&price_response, &order) {
+        return Err(AggregatorError::LimitPriceViolated.into());
+    }
+    sub_trades_from_price_route(&price_response.price_route, chain)
+}
+
+/// Maximal deviation of the summed up percents of all routes from 100.
+const MAX_ROUTE_PERCENT_DEVIATION: f64 = 0.01;
+
+/// The trades of all swap exchanges of all routes. The amounts of a swap exchange already are
+/// its share of the volume of its route, so the trades of all routes together make up the full
+/// volume of the order, as long as the percents of the routes add up to 100.
+fn sub_trades_from_price_route(
+    price_route: &PriceRoute,
+    chain: &ChainConfig,
+) -> Result<Vec<SubTrade>> {
+    if price_route.best_route.is_empty() {
+        return Err(AggregatorError::NoLiquidity.into());
+    }
+    let percent: f64 = price_route
+        .best_route
+        .iter()
+        .map(|route| route.percent)
+        .sum();
+    if (percent - 100.0).abs() > MAX_ROUTE_PERCENT_DEVIATION {
+        return Err(anyhow!(
+            "paraswap routes cover {}% instead of the full volume",
+            percent
+        ));
     }
-    Ok(((matched_orders), sub_trades))
+    Ok(price_route
+        .best_route
+        .iter()
+        .flat_map(|route| &route.swaps)
+        .flat_map(|swap| {
+            let src_token = chain.over_write_native_token(swap.src_token);
+            let dest_token = chain.over_write_native_token(swap.dest_token);
+            swap.swap_exchanges.iter().map(move |trade| SubTrade {
+                src_token,
+                dest_token,
+                src_amount: trade.src_amount,
+                dest_amount: trade.dest_amount,
+            })
+        })
+        .collect())
 }
 fn satisfies_limit_price_with_buffer(price_response: &Root, order: &OrderModel) -> bool {
     (price_response.price_route.dest_amount.ge(&order
@@ -536,81 +1416,74 @@ fn satisfies_limit_price_with_buffer(price_response: &Root, order: &OrderModel)
             && !order.is_sell_order)
 }
 
+/// Adds up the amounts of all sub trades on the same token pair and in the same direction,
+/// across all legs and orders.
 fn get_splitted_trade_amounts_from_trading_vec(
     single_trade_results: Vec<SubTrade>,
-) -> HashMap<(H160, H160), (U256, U256)> {
+) -> Result<HashMap<(H160, H160), (U256, U256)>> {
     let mut splitted_trade_amounts: HashMap<(H160, H160), (U256, U256)> = HashMap::new();
     for sub_trade in single_trade_results {
-        splitted_trade_amounts
+        let (in_amounts, out_amounts) = splitted_trade_amounts
             .entry((sub_trade.src_token, sub_trade.dest_token))
-            .and_modify(|(in_amounts, out_amounts)| {
-                in_amounts.checked_add(sub_trade.src_amount).unwrap();
-                out_amounts.checked_add(sub_trade.dest_amount).unwrap();
-            })
-            .or_insert((sub_trade.src_amount, sub_trade.dest_amount));
+            .or_default();
+        *in_amounts = in_amounts
+            .checked_add(sub_trade.src_amount)
+            .ok_or_else(|| anyhow!("sold volume of {:?} overflows", sub_trade.src_token))?;
+        *out_amounts = out_amounts
+            .checked_add(sub_trade.dest_amount)
+            .ok_or_else(|| anyhow!("bought volume of {:?} overflows", sub_trade.dest_token))?;
     }
-    splitted_trade_amounts
+    Ok(splitted_trade_amounts)
 }
 
-#[derive(Debug)]
+/// A left-over amount of the sub trades, which has to be traded for at least the buy amount.
+#[derive(Debug, PartialEq)]
 struct TradeAmount {
-    must_satisfy_limit_price: bool,
     sell_amount: U256,
     buy_amount: U256,
 }
 
+/// Nets the sub trades in opposite directions on the same pair against each other. The
+/// left-over amount is traded in the direction of the surplus token: its excess is sold, for
+/// at least the volume of the other token the sub trades still lack. This keeps every leg
+/// supplied with exactly the amounts its route expects.
 fn get_trade_amounts_without_cow_volumes(
     splitted_trade_amounts: &HashMap<(H160, H160), (U256, U256)>,
 ) -> Result<HashMap<(H160, H160), TradeAmount>> {
     let mut updated_traded_amounts = HashMap::new();
-    for (pair, entry_amouts) in splitted_trade_amounts {
-        let (src_token, dest_token) = pair;
-        if updated_traded_amounts.get(pair).is_some()
-            || updated_traded_amounts
-                .get(&(*dest_token, *src_token))
-                .is_some()
-        {
-            continue;
-        }
-        if let Some(opposite_amounts) = splitted_trade_amounts.get(&(*dest_token, *src_token)) {
-            if entry_amouts.1.gt(&opposite_amounts.0) {
-                updated_traded_amounts.insert(
-                    (*dest_token, *src_token),
-                    TradeAmount {
-                        must_satisfy_limit_price: false,
-                        sell_amount: entry_amouts.1.checked_sub(opposite_amounts.0).unwrap(),
-                        buy_amount: U256::zero(),
-                    },
-                );
-            } else if entry_amouts.0.gt(&opposite_amounts.1) {
-                updated_traded_amounts.insert(
-                    (*src_token, *dest_token),
-                    TradeAmount {
-                        must_satisfy_limit_price: false,
-                        sell_amount: entry_amouts.0.checked_sub(opposite_amounts.1).unwrap(),
-                        buy_amount: U256::zero(),
-                    },
-                );
-            } else {
-                updated_traded_amounts.insert(
-                    (*src_token, *dest_token),
-                    TradeAmount {
-                        must_satisfy_limit_price: false,
-                        sell_amount: opposite_amounts.0.checked_sub(entry_amouts.1).unwrap(),
-                        buy_amount: U256::zero(),
-                    },
-                );
-            }
-        } else {
-            updated_traded_amounts.insert(
-                (*src_token, *dest_token),
+    for (&(src_token, dest_token), &(sold, bought)) in splitted_trade_amounts {
+        let (opposite_sold, opposite_bought) = splitted_trade_amounts
+            .get(&(dest_token, src_token))
+            .copied()
+            .unwrap_or_default();
+        let excess = sold.checked_sub(opposite_bought);
+        let opposite_excess = opposite_sold.checked_sub(bought);
+        let trade_amount = match (excess, opposite_excess) {
+            (Some(excess), None) if !excess.is_zero() => TradeAmount {
+                sell_amount: excess,
+                buy_amount: bought - opposite_sold,
+            },
+            // With a surplus of both tokens, only one of them is sold. The other one stays
+            // in the settlement.
+            (Some(excess), Some(opposite_excess))
+                if !excess.is_zero() && (src_token < dest_token || opposite_excess.is_zero()) =>
+            {
                 TradeAmount {
-                    must_satisfy_limit_price: false,
-                    sell_amount: splitted_trade_amounts.get(pair).unwrap().0,
-                    buy_amount: splitted_trade_amounts.get(pair).unwrap().1,
-                },
-            );
-        }
+                    sell_amount: excess,
+                    buy_amount: U256::zero(),
+                }
+            }
+            // The opposite direction sells the surplus, or the cow covers both volumes.
+            (_, Some(_)) => continue,
+            (_, None) => {
+                return Err(anyhow!(
+                    "sub trades on the pair {:?} lack volume of {:?}",
+                    (src_token, dest_token),
+                    dest_token
+                ))
+            }
+        };
+        updated_traded_amounts.insert((src_token, dest_token), trade_amount);
     }
     Ok(updated_traded_amounts)
 }
@@ -629,87 +1502,119 @@ fn contain_cow(splitted_trade_amounts: &[SubTrade]) -> bool {
     }
     false
 }
-fn one_token_is_already_in_settlement(
-    solution: &SettledBatchAuctionModel,
-    swap_info: &(SwapQuery, SwapResponse),
-) -> u64 {
-    let tokens: Vec<H160> = solution.prices.keys().copied().collect();
-    if tokens.contains(&swap_info.0.sell_token) || tokens.contains(&swap_info.0.buy_token) {
-        1u64
-    } else {
-        0u64
+
+/// Adds the swaps of the route as executions of the instance amms. Each route gets its own
+/// sequence in the execution plan, and its swaps are positioned in the order they need to be
+/// executed, so that intermediate tokens are received before they are sold.
+fn insert_amm_executions(solution: &mut SettledBatchAuctionModel, route: &AmmRoute, sequence: u32) {
+    for (position, swap) in route.swaps.iter().enumerate() {
+        solution
+            .amms
+            .entry(swap.amm_id)
+            .or_insert_with(|| UpdatedAmmModel {
+                execution: Vec::new(),
+            })
+            .execution
+            .push(ExecutedAmmModel {
+                sell_token: swap.sell_token,
+                buy_token: swap.buy_token,
+                exec_sell_amount: swap.sell_amount,
+                exec_buy_amount: swap.buy_amount,
+                exec_plan: Some(ExecutionPlanCoordinatesModel {
+                    sequence,
+                    position: position as u32,
+                }),
+            });
     }
 }
-fn over_write_eth_with_weth_token(token: H160) -> H160 {
-    if token.eq(&"eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee".parse().unwrap()) {
-        "c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2".parse().unwrap()
-    } else {
-        token
+
+/// The clearing prices of all trades of a candidate.
+fn candidate_clearing_prices(
+    swap_results: &[LiquidityTrade],
+    splitted_trade_amounts: &HashMap<(H160, H160), (U256, U256)>,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    chain: &ChainConfig,
+) -> Result<ClearingPrices> {
+    let edges = swap_results
+        .iter()
+        .map(|trade| trade_edges(splitted_trade_amounts, trade))
+        .collect::<Result<Vec<_>>>()?;
+    compute_clearing_prices(&edges.concat(), tokens, chain.wrapped_native_token)
+}
+
+/// Whether the full amounts of the order satisfy its limit price at the clearing prices.
+fn clears_within_limit_price(order: &OrderModel, prices: &HashMap<H160, U256>) -> bool {
+    match (prices.get(&order.sell_token), prices.get(&order.buy_token)) {
+        (Some(sell_price), Some(buy_price)) => {
+            order.sell_amount.full_mul(*sell_price) >= order.buy_amount.full_mul(*buy_price)
+        }
+        _ => false,
     }
 }
 
-const SCALING_FACTOR: u64 = 10000u64;
-pub fn insert_new_price(
-    solution: &mut SettledBatchAuctionModel,
-    splitted_trade_amounts: &HashMap<(H160, H160), (U256, U256)>,
-    query: SwapQuery,
-    swap: SwapResponse,
+/// Checks that all matched orders clear within their limit price at the clearing prices.
+fn check_limit_prices(
+    matched_orders: &[(usize, OrderModel)],
+    prices: &HashMap<H160, U256>,
 ) -> Result<()> {
-    let src_token = query.sell_token;
-    let dest_token = query.buy_token;
-    let (sell_amount, buy_amount) = match (
-        splitted_trade_amounts.get(&(src_token, dest_token)),
-        splitted_trade_amounts.get(&(dest_token, src_token)),
+    match matched_orders
+        .iter()
+        .find(|(_, order)| !clears_within_limit_price(order, prices))
+    {
+        Some((index, _)) => Err(anyhow!(
+            "order {} does not clear within its limit price",
+            index
+        )),
+        None => Ok(()),
+    }
+}
+
+/// The edges of the trade for the clearing prices. The left-over trade of a cow is combined
+/// with the cow volume of the opposite direction, and a ring relates all of its tokens.
+fn trade_edges(
+    splitted_trade_amounts: &HashMap<(H160, H160), (U256, U256)>,
+    trade: &LiquidityTrade,
+) -> Result<Vec<TradeEdge>> {
+    if let TradeExecution::Ring(ring_match) = &trade.execution {
+        return Ok(ring_match
+            .orders
+            .iter()
+            .map(|(_, order)| TradeEdge {
+                sell_token: order.sell_token,
+                buy_token: order.buy_token,
+                sell_amount: ring_match.prices[&order.buy_token],
+                buy_amount: ring_match.prices[&order.sell_token],
+            })
+            .collect());
+    }
+    if let TradeExecution::Cow(cow_match) = &trade.execution {
+        let (sell_amount, buy_amount) = cow_match.clearing_amounts();
+        return Ok(vec![TradeEdge {
+            sell_token: trade.sell_token,
+            buy_token: trade.buy_token,
+            sell_amount,
+            buy_amount,
+        }]);
+    }
+    let (cow_sell_amount, cow_buy_amount) = match (
+        splitted_trade_amounts.get(&(trade.sell_token, trade.buy_token)),
+        splitted_trade_amounts.get(&(trade.buy_token, trade.sell_token)),
     ) {
-        (Some((_sell_amount, _)), Some((buy_amount, substracted_sell_amount))) => {
+        (Some(_), Some((buy_amount, substracted_sell_amount))) => {
             (*substracted_sell_amount, *buy_amount)
         }
-        (Some((_, _)), None) => (U256::zero(), U256::zero()),
-        (None, Some((_, _))) => (U256::zero(), U256::zero()),
-        (None, None) => (U256::zero(), U256::zero()),
+        _ => (U256::zero(), U256::zero()),
     };
-    let (sell_amount, buy_amount) = (
-        sell_amount.checked_add(swap.sell_amount).unwrap(),
-        buy_amount.checked_add(swap.buy_amount).unwrap(),
-    );
-
-    match (
-        solution.prices.clone().get(&query.sell_token),
-        solution.prices.clone().get(&query.buy_token),
-    ) {
-        (Some(_), Some(_)) => return Err(anyhow!("can't deal with such a ring")),
-        (Some(price_sell_token), None) => {
-            solution.prices.insert(
-                query.buy_token,
-                price_sell_token
-                    .checked_mul(sell_amount)
-                    .unwrap()
-                    .checked_div(buy_amount)
-                    .unwrap(),
-            );
-        }
-        (None, Some(price_buy_token)) => {
-            solution.prices.insert(
-                query.sell_token,
-                price_buy_token
-                    .checked_mul(buy_amount)
-                    .unwrap()
-                    .checked_div(sell_amount)
-                    .unwrap(),
-            );
-        }
-        (None, None) => {
-            solution.prices.insert(
-                query.sell_token,
-                buy_amount.checked_mul(U256::from(SCALING_FACTOR)).unwrap(),
-            );
-            solution.prices.insert(
-                query.buy_token,
-                sell_amount.checked_mul(U256::from(SCALING_FACTOR)).unwrap(),
-            );
-        }
-    }
-    Ok(())
+    Ok(vec![TradeEdge {
+        sell_token: trade.sell_token,
+        buy_token: trade.buy_token,
+        sell_amount: cow_sell_amount
+            .checked_add(trade.sell_amount())
+            .ok_or_else(|| anyhow!("sell amount overflows"))?,
+        buy_amount: cow_buy_amount
+            .checked_add(trade.buy_amount())
+            .ok_or_else(|| anyhow!("buy amount overflows"))?,
+    }])
 }
 
 #[cfg(test)]
@@ -717,169 +1622,588 @@ mod tests {
     use super::*;
     use crate::models::batch_auction_model::CostModel;
     use crate::models::batch_auction_model::FeeModel;
+    use crate::models::batch_auction_model::{
+        AmmModel, AmmParameters, ConstantProductPoolParameters,
+    };
+    use crate::solve::amm_solver::AmmSwap;
+    use crate::solve::paraswap_solver::api::{BestRoute, Swap, SwapExchange};
+    use crate::solve::zeroex_solver::api::{ZeroExApi, ZeroExResponseError};
+    use crate::solve::zeroex_solver::ZeroExSolver;
     use core::array::IntoIter;
+    use maplit::btreemap;
+    use num::BigRational;
     use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tracing_test::traced_test;
 
+    fn test_context() -> SolverContext {
+        SolverContext::new(
+            &"https://staging-openethereum.mainnet.gnosisdev.com"
+                .parse()
+                .unwrap(),
+            ChainConfig::for_chain_id(1u64).unwrap(),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn partially_fillable_orders_are_filled_within_their_limit_price() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let mut amm_router = AmmRouter::new(
+            btreemap! {
+                0 => AmmModel {
+                    parameters: AmmParameters::ConstantProduct(ConstantProductPoolParameters {
+                        reserves: btreemap! { token_a => 1_000.into(), token_b => 1_000.into() },
+                    }),
+                    fee: BigRational::new(3.into(), 1000.into()),
+                    cost: CostModel::default(),
+                    mandatory: false,
+                },
+            },
+            &BTreeMap::new(),
+        );
+        let order = OrderModel {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: 500.into(),
+            buy_amount: 450.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: true,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: token_a,
+            },
+        };
+        let fill_or_kill_order = OrderModel {
+            allow_partial_fill: false,
+            ..order.clone()
+        };
+        assert!(quote_order_from_amms(&order, &amm_router, order.sell_amount).is_none());
+
+        let results =
+            get_partial_fills_from_amms(vec![(1, order), (2, fill_or_kill_order)], &mut amm_router);
+        assert_eq!(results.len(), 1);
+        let ((index, filled_order), trade) = &results[0];
+        assert_eq!(*index, 1);
+        assert!(filled_order.sell_amount > U256::zero());
+        assert!(filled_order.sell_amount < 500.into());
+        assert_eq!(trade.sell_amount(), filled_order.sell_amount);
+        // The executed part respects the limit price of 0.9
+        assert!(trade.buy_amount() * 10 >= trade.sell_amount() * 9);
+        assert!(filled_order.buy_amount * 10 >= filled_order.sell_amount * 9);
+    }
+
     #[test]
-    fn check_for_market_order_with_different_decimal() {
-        let mim: H160 = "99d8a9c45b2eca8864373a26d1459e3dff1e17f3".parse().unwrap();
-        let usdc: H160 = "a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".parse().unwrap();
-        let mim_usdc_sell_order = OrderModel {
-            sell_token: mim,
-            buy_token: usdc,
-            sell_amount: 85670806275371642755219456u128.into(),
-            buy_amount: 85593297939394u128.into(),
+    fn orders_are_settled_against_the_best_liquidity_order() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: 500.into(),
+            buy_amount: 450.into(),
             is_sell_order: true,
             is_liquidity_order: false,
             allow_partial_fill: false,
-            cost: CostModel {
-                amount: U256::from(0u32),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
-            },
+            cost: CostModel::default(),
             fee: FeeModel {
-                amount: U256::from(0u32),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+                amount: U256::zero(),
+                token: token_a,
             },
         };
-        let tokens = BTreeMap::from_iter(IntoIter::new([
+        let liquidity_order = OrderModel {
+            sell_token: token_b,
+            buy_token: token_a,
+            sell_amount: 1_000.into(),
+            buy_amount: 1_000.into(),
+            is_sell_order: true,
+            is_liquidity_order: true,
+            allow_partial_fill: true,
+            ..order.clone()
+        };
+        let liquidity_orders = vec![
+            (1, liquidity_order.clone()),
             (
-                mim,
-                TokenInfoModel {
-                    decimals: Some(18u8),
-                    external_price: Some(0.00040788388716066107f64),
-                    ..Default::default()
+                2,
+                OrderModel {
+                    sell_amount: 1_100.into(),
+                    ..liquidity_order.clone()
                 },
             ),
+            // would pay the most, but can not be executed partially
             (
-                usdc,
-                TokenInfoModel {
-                    decimals: Some(6u8),
-                    external_price: Some(405525120.6406718f64),
-                    ..Default::default()
+                3,
+                OrderModel {
+                    sell_amount: 1_200.into(),
+                    allow_partial_fill: false,
+                    ..liquidity_order
                 },
             ),
-        ]));
-        assert!(is_market_order(&tokens, mim_usdc_sell_order).unwrap());
+        ];
+
+        let (results, remaining_orders) = get_swaps_for_orders_from_liquidity_orders(
+            vec![(0, order.clone())],
+            &liquidity_orders,
+            &AmmRouter::default(),
+            1,
+        );
+        assert!(remaining_orders.is_empty());
+        let ((index, _), trade) = &results[0];
+        assert_eq!(*index, 0);
+        assert_eq!(trade.sell_amount(), 500.into());
+        assert_eq!(trade.buy_amount(), 550.into());
+        assert!(matches!(
+            trade.execution,
+            TradeExecution::LiquidityOrder(2, _)
+        ));
+
+        // Liquidity orders are optional, the order is left for other liquidity.
+        let (results, remaining_orders) = get_swaps_for_orders_from_liquidity_orders(
+            vec![(0, order)],
+            &liquidity_orders,
+            &AmmRouter::default(),
+            0,
+        );
+        assert!(results.is_empty());
+        assert_eq!(remaining_orders.len(), 1);
     }
 
     #[test]
-    fn check_for_market_order() {
-        let dai: H160 = "4e3fbd56cd56c3e72c1403e103b45db9da5b9d2b".parse().unwrap();
-        let usdc: H160 = "d533a949740bb3306d119cc777fa900ba034cd52".parse().unwrap();
-
-        let dai_usdc_sell_order = OrderModel {
-            sell_token: dai,
-            buy_token: usdc,
-            sell_amount: 1_001_000_000_000_000_000u128.into(),
-            buy_amount: 1_000_000u128.into(),
+    fn orders_are_traded_net_of_fee() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 900.into(),
             is_sell_order: true,
             is_liquidity_order: false,
             allow_partial_fill: false,
-            cost: CostModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
-            },
+            cost: CostModel::default(),
             fee: FeeModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+                amount: 10.into(),
+                token: sell_token,
             },
         };
-        let dai_usdc_buy_order = OrderModel {
-            sell_token: dai,
-            buy_token: usdc,
-            sell_amount: 1_001_000_000_000_000_000u128.into(),
-            buy_amount: 1_000_000u128.into(),
-            is_sell_order: false,
-            is_liquidity_order: false,
-            allow_partial_fill: false,
-            cost: CostModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+        let net_order = order_net_of_fee(order.clone()).unwrap();
+        assert_eq!(net_order.sell_amount, 990.into());
+        assert_eq!(net_order.buy_amount, 900.into());
+
+        let fee_in_buy_token = OrderModel {
+            fee: FeeModel {
+                amount: 10.into(),
+                token: buy_token,
             },
+            ..order.clone()
+        };
+        assert!(order_net_of_fee(fee_in_buy_token).is_err());
+
+        let fee_exceeding_sell_amount = OrderModel {
             fee: FeeModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+                amount: 1_000.into(),
+                token: sell_token,
             },
+            ..order
         };
-        let tokens = BTreeMap::from_iter(IntoIter::new([
-            (
-                dai,
-                TokenInfoModel {
-                    decimals: Some(18u8),
-                    external_price: Some(1.00f64),
-                    ..Default::default()
+        assert!(order_net_of_fee(fee_exceeding_sell_amount).is_err());
+    }
+
+    #[test]
+    fn amm_executions_follow_the_route_order() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let route = AmmRoute {
+            swaps: vec![
+                AmmSwap {
+                    amm_id: 7,
+                    sell_token: token_a,
+                    buy_token: token_c,
+                    sell_amount: 100.into(),
+                    buy_amount: 90.into(),
                 },
-            ),
-            (
-                usdc,
-                TokenInfoModel {
-                    decimals: Some(6u8),
-                    external_price: Some(1000000000000.0f64),
-                    ..Default::default()
+                AmmSwap {
+                    amm_id: 3,
+                    sell_token: token_c,
+                    buy_token: token_b,
+                    sell_amount: 90.into(),
+                    buy_amount: 80.into(),
                 },
-            ),
-        ]));
-        assert!(is_market_order(&tokens, dai_usdc_sell_order.clone()).unwrap());
-        assert!(is_market_order(&tokens, dai_usdc_buy_order.clone()).unwrap());
+            ],
+        };
+        let mut solution = SettledBatchAuctionModel::default();
+        insert_amm_executions(&mut solution, &route, 0);
+        insert_amm_executions(&mut solution, &route, 1);
 
-        let tokens = BTreeMap::from_iter(IntoIter::new([
-            (
-                dai,
-                TokenInfoModel {
-                    decimals: Some(18u8),
-                    external_price: Some(1.00f64),
-                    ..Default::default()
-                },
-            ),
-            (
-                usdc,
-                TokenInfoModel {
-                    decimals: Some(6u8),
-                    external_price: Some(1020000000000.0f64),
-                    ..Default::default()
+        assert!(solution.has_execution_plan());
+        let plans: Vec<(u32, u32)> = solution.amms[&7]
+            .execution
+            .iter()
+            .chain(solution.amms[&3].execution.iter())
+            .map(|execution| {
+                let plan = execution.exec_plan.as_ref().unwrap();
+                (plan.sequence, plan.position)
+            })
+            .collect();
+        assert_eq!(plans, vec![(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert_eq!(solution.amms[&3].execution[0].exec_sell_amount, 90.into());
+    }
+
+    #[test]
+    fn cow_netting_keeps_the_direction_and_buy_amounts() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let amounts = |entries: &[(H160, H160, u64, u64)]| {
+            entries
+                .iter()
+                .map(|&(src_token, dest_token, sold, bought)| {
+                    ((src_token, dest_token), (sold.into(), bought.into()))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        let trade_amount = |sell_amount: u64, buy_amount: u64| TradeAmount {
+            sell_amount: sell_amount.into(),
+            buy_amount: buy_amount.into(),
+        };
+
+        // 100 a are sold for 200 b, of which 150 b are sold for 70 a in the opposite direction.
+        let netted = get_trade_amounts_without_cow_volumes(&amounts(&[
+            (token_a, token_b, 100, 200),
+            (token_b, token_a, 150, 70),
+            (token_b, token_c, 10, 20),
+        ]))
+        .unwrap();
+        assert_eq!(netted.len(), 2);
+        assert_eq!(netted[&(token_a, token_b)], trade_amount(30, 50));
+        assert_eq!(netted[&(token_b, token_c)], trade_amount(10, 20));
+
+        // The opposite direction sells its surplus of b.
+        let netted = get_trade_amounts_without_cow_volumes(&amounts(&[
+            (token_a, token_b, 100, 200),
+            (token_b, token_a, 250, 120),
+        ]))
+        .unwrap();
+        assert_eq!(netted.len(), 1);
+        assert_eq!(netted[&(token_b, token_a)], trade_amount(50, 20));
+
+        // With a surplus of both tokens, only one of them is sold.
+        let netted = get_trade_amounts_without_cow_volumes(&amounts(&[
+            (token_a, token_b, 100, 200),
+            (token_b, token_a, 250, 70),
+        ]))
+        .unwrap();
+        assert_eq!(netted.len(), 1);
+        assert_eq!(netted[&(token_a, token_b)], trade_amount(30, 0));
+
+        // Without a surplus of any token, nothing can be traded.
+        assert!(get_trade_amounts_without_cow_volumes(&amounts(&[
+            (token_a, token_b, 100, 200),
+            (token_b, token_a, 150, 120),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn orders_clear_within_their_limit_price() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 2_000.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let prices = |sell_price: u64, buy_price: u64| {
+            HashMap::from([
+                (sell_token, sell_price.into()),
+                (buy_token, buy_price.into()),
+            ])
+        };
+        assert!(clears_within_limit_price(&order, &prices(2, 1)));
+        assert!(!clears_within_limit_price(&order, &prices(199, 100)));
+        assert!(!clears_within_limit_price(&order, &HashMap::new()));
+    }
+
+    #[test]
+    fn orders_on_the_same_pair_clear_at_their_average_rate() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let mut amm_router = AmmRouter::new(
+            btreemap! {
+                0 => AmmModel {
+                    parameters: AmmParameters::ConstantProduct(ConstantProductPoolParameters {
+                        reserves: btreemap! { token_a => 1_000.into(), token_b => 1_000.into() },
+                    }),
+                    fee: BigRational::new(3.into(), 1000.into()),
+                    cost: CostModel::default(),
+                    mandatory: false,
                 },
-            ),
-        ]));
-        assert!(!is_market_order(&tokens, dai_usdc_sell_order).unwrap());
-        assert!(!is_market_order(&tokens, dai_usdc_buy_order).unwrap());
-        let weth: H160 = "4e3fbd56cd56c3e72c1403e103b45db9da5b9d2b".parse().unwrap();
-        let usdc_weth_order = OrderModel {
-            sell_token: usdc,
-            buy_token: weth,
-            sell_amount: 4_002_000_000u128.into(),
-            buy_amount: 1_000_000_000_000_000_000u128.into(),
+            },
+            &BTreeMap::new(),
+        );
+        let order = |buy_amount: u64| OrderModel {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: 100.into(),
+            buy_amount: buy_amount.into(),
             is_sell_order: true,
             is_liquidity_order: false,
             allow_partial_fill: false,
-            cost: CostModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: token_a,
+            },
+        };
+        // The first order receives 90 b and the second one 75 b from the amm.
+        let (results, remaining_orders) =
+            get_swaps_for_orders_from_amms(vec![(1, order(85)), (2, order(70))], &mut amm_router);
+        assert!(remaining_orders.is_empty());
+        let (matched_orders, swap_results): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+        let chain = ChainConfig::for_chain_id(1u64).unwrap();
+        let prices =
+            candidate_clearing_prices(&swap_results, &HashMap::new(), &BTreeMap::new(), &chain)
+                .unwrap();
+
+        // At the average rate of 82.5 b, the first order violates its limit price.
+        let err = check_limit_prices(&matched_orders, &prices.prices).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "order 1 does not clear within its limit price"
+        );
+        assert!(check_limit_prices(&matched_orders[1..], &prices.prices).is_ok());
+    }
+
+    #[test]
+    fn sub_trades_on_the_same_pair_are_added_up() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let sub_trade = |src_token, dest_token, src_amount: u64, dest_amount: u64| SubTrade {
+            src_token,
+            dest_token,
+            src_amount: src_amount.into(),
+            dest_amount: dest_amount.into(),
+        };
+        let sub_trades = vec![
+            // two legs of the route of a first order
+            sub_trade(token_a, token_b, 100, 200),
+            sub_trade(token_a, token_b, 50, 99),
+            sub_trade(token_b, token_c, 299, 30),
+            // a second order on the same pair, and one in the opposite direction
+            sub_trade(token_a, token_b, 10, 20),
+            sub_trade(token_b, token_a, 40, 20),
+        ];
+        let amounts = get_splitted_trade_amounts_from_trading_vec(sub_trades).unwrap();
+        assert_eq!(amounts.len(), 3);
+        assert_eq!(amounts[&(token_a, token_b)], (160.into(), 319.into()));
+        assert_eq!(amounts[&(token_b, token_c)], (299.into(), 30.into()));
+        assert_eq!(amounts[&(token_b, token_a)], (40.into(), 20.into()));
+
+        let sub_trades = vec![
+            sub_trade(token_a, token_b, 1, 1),
+            SubTrade {
+                src_amount: U256::max_value(),
+                ..sub_trade(token_a, token_b, 0, 1)
             },
+        ];
+        assert!(get_splitted_trade_amounts_from_trading_vec(sub_trades).is_err());
+    }
+
+    /// Quotes every swap at a rate of two buy tokens per sell token.
+    struct FakeZeroExApi;
+
+    #[async_trait::async_trait]
+    impl ZeroExApi for FakeZeroExApi {
+        async fn get_swap(&self, query: SwapQuery) -> Result<SwapResponse, ZeroExResponseError> {
+            let (sell_amount, buy_amount) = match (query.sell_amount, query.buy_amount) {
+                (Some(sell_amount), _) => (sell_amount, sell_amount * 2),
+                (None, Some(buy_amount)) => (buy_amount / 2, buy_amount),
+                (None, None) => return Err(ZeroExResponseError::ServerError("no amount".into())),
+            };
+            Ok(SwapResponse {
+                sell_amount,
+                buy_amount,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn zeroex_is_queried_through_the_context() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let order = |buy_amount: u64| OrderModel {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: 100.into(),
+            buy_amount: buy_amount.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
             fee: FeeModel {
-                amount: U256::from(0),
-                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+                amount: U256::zero(),
+                token: token_a,
             },
         };
-        let tokens = BTreeMap::from_iter(IntoIter::new([
-            (
-                weth,
-                TokenInfoModel {
-                    decimals: Some(18u8),
-                    external_price: Some(1.00f64),
+        let mut context = test_context();
+        context.zeroex = Some(Arc::new(ZeroExSolver {
+            client: Box::new(FakeZeroExApi),
+        }));
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        let swaps = get_swaps_for_orders_from_zeroex(vec![(0, order(150))], &context, deadline)
+            .await
+            .unwrap();
+        assert_eq!(swaps.len(), 1);
+        assert_eq!(swaps[0].1 .1.buy_amount, 200.into());
+        // Swaps violating the limit price are left out.
+        assert!(
+            get_swaps_for_orders_from_zeroex(vec![(0, order(250))], &context, deadline)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        context.zeroex = None;
+        assert!(
+            get_swaps_for_orders_from_zeroex(vec![(0, order(150))], &context, deadline)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_paraswap_queries_only_drop_their_order() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let order = |sell_amount: u64| OrderModel {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: sell_amount.into(),
+            buy_amount: 1.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: token_a,
+            },
+        };
+        let orders = vec![
+            (0, order(10)),
+            (1, order(20)),
+            (2, order(30)),
+            (3, order(40)),
+        ];
+        let query = |order: OrderModel| async move {
+            match order.sell_amount.as_u64() {
+                20 => Err(anyhow!("no route")),
+                40 => futures::future::pending().await,
+                _ => Ok(vec![SubTrade {
+                    src_token: order.sell_token,
+                    dest_token: order.buy_token,
+                    src_amount: order.sell_amount,
+                    dest_amount: order.buy_amount,
+                }]),
+            }
+        };
+        let deadline = Instant::now() + Duration::from_millis(10);
+        let result = collect_sub_trades_per_order(orders, deadline, query).await;
+
+        let matched: Vec<usize> = result
+            .matched_orders
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(matched, vec![0, 2]);
+        assert_eq!(result.sub_trades.len(), 2);
+        let failed: Vec<(usize, String)> = result
+            .failed_orders
+            .iter()
+            .map(|(index, err)| (*index, err.to_string()))
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                (1, "no route".to_string()),
+                (3, "deadline reached while querying paraswap".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sub_trades_are_taken_from_all_paraswap_routes() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let swap = |src_token, dest_token, amounts: &[(u64, u64)]| Swap {
+            src_token,
+            dest_token,
+            swap_exchanges: amounts
+                .iter()
+                .map(|&(src_amount, dest_amount)| SwapExchange {
+                    src_amount: src_amount.into(),
+                    dest_amount: dest_amount.into(),
                     ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut price_route = PriceRoute {
+            best_route: vec![
+                BestRoute {
+                    percent: 60.0,
+                    swaps: vec![swap(token_a, token_b, &[(400, 800), (200, 390)])],
                 },
-            ),
-            (
-                usdc,
-                TokenInfoModel {
-                    decimals: Some(6u8),
-                    external_price: Some(400000000.0f64),
-                    ..Default::default()
+                BestRoute {
+                    percent: 40.0,
+                    swaps: vec![
+                        swap(token_a, token_c, &[(400, 100)]),
+                        swap(token_c, token_b, &[(100, 790)]),
+                    ],
                 },
-            ),
-        ]));
-        assert!(is_market_order(&tokens, usdc_weth_order).unwrap());
+            ],
+            ..Default::default()
+        };
+        let chain = ChainConfig::for_chain_id(1).unwrap();
+
+        let sub_trades = sub_trades_from_price_route(&price_route, &chain).unwrap();
+        let sold: U256 = sub_trades
+            .iter()
+            .filter(|trade| trade.src_token == token_a)
+            .map(|trade| trade.src_amount)
+            .fold(U256::zero(), |sum, amount| sum + amount);
+        let bought: U256 = sub_trades
+            .iter()
+            .filter(|trade| trade.dest_token == token_b)
+            .map(|trade| trade.dest_amount)
+            .fold(U256::zero(), |sum, amount| sum + amount);
+        assert_eq!(sub_trades.len(), 4);
+        assert_eq!(sold, 1_000.into());
+        assert_eq!(bought, 1_980.into());
+
+        price_route.best_route.pop();
+        assert!(sub_trades_from_price_route(&price_route, &chain).is_err());
+        price_route.best_route.clear();
+        assert!(sub_trades_from_price_route(&price_route, &chain).is_err());
     }
 
     #[tokio::test]
@@ -907,30 +2231,33 @@ mod tests {
             },
         };
 
-        let solution = solve(BatchAuctionModel {
-            tokens: BTreeMap::from_iter(IntoIter::new([
-                (
-                    dai,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    gno,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-            ])),
-            orders: BTreeMap::from_iter(IntoIter::new([
-                (1, dai_gno_order.clone()),
-                (2, dai_gno_order.clone()),
-                (3, dai_gno_order),
-            ])),
-            ..Default::default()
-        })
+        let solution = solve(
+            BatchAuctionModel {
+                tokens: BTreeMap::from_iter(IntoIter::new([
+                    (
+                        dai,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        gno,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                ])),
+                orders: BTreeMap::from_iter(IntoIter::new([
+                    (1, dai_gno_order.clone()),
+                    (2, dai_gno_order.clone()),
+                    (3, dai_gno_order),
+                ])),
+                ..Default::default()
+            },
+            &test_context(),
+        )
         .await
         .unwrap();
 
@@ -980,33 +2307,39 @@ mod tests {
                 token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
             },
         };
-        let solution = solve(BatchAuctionModel {
-            tokens: BTreeMap::from_iter(IntoIter::new([
-                (
-                    dai,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    gno,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    weth,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-            ])),
-            orders: BTreeMap::from_iter(IntoIter::new([(1, gno_weth_order), (2, dai_gno_order)])),
-            ..Default::default()
-        })
+        let solution = solve(
+            BatchAuctionModel {
+                tokens: BTreeMap::from_iter(IntoIter::new([
+                    (
+                        dai,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        gno,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        weth,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                ])),
+                orders: BTreeMap::from_iter(IntoIter::new([
+                    (1, gno_weth_order),
+                    (2, dai_gno_order),
+                ])),
+                ..Default::default()
+            },
+            &test_context(),
+        )
         .await
         .unwrap();
 
@@ -1057,33 +2390,39 @@ mod tests {
                 token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
             },
         };
-        let solution = solve(BatchAuctionModel {
-            tokens: BTreeMap::from_iter(IntoIter::new([
-                (
-                    dai,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    gno,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    bal,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-            ])),
-            orders: BTreeMap::from_iter(IntoIter::new([(1, bal_dai_order), (2, dai_gno_order)])),
-            ..Default::default()
-        })
+        let solution = solve(
+            BatchAuctionModel {
+                tokens: BTreeMap::from_iter(IntoIter::new([
+                    (
+                        dai,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        gno,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        bal,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                ])),
+                orders: BTreeMap::from_iter(IntoIter::new([
+                    (1, bal_dai_order),
+                    (2, dai_gno_order),
+                ])),
+                ..Default::default()
+            },
+            &test_context(),
+        )
         .await
         .unwrap();
 
@@ -1113,29 +2452,32 @@ mod tests {
                 token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
             },
         };
-        let solution = solve(BatchAuctionModel {
-            tokens: BTreeMap::from_iter(IntoIter::new([
-                (
-                    free,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-                (
-                    weth,
-                    TokenInfoModel {
-                        decimals: Some(18u8),
-                        ..Default::default()
-                    },
-                ),
-            ])),
-            orders: BTreeMap::from_iter(IntoIter::new([
-                (1, free_weth_order.clone()),
-                (2, free_weth_order),
-            ])),
-            ..Default::default()
-        })
+        let solution = solve(
+            BatchAuctionModel {
+                tokens: BTreeMap::from_iter(IntoIter::new([
+                    (
+                        free,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                    (
+                        weth,
+                        TokenInfoModel {
+                            decimals: Some(18u8),
+                            ..Default::default()
+                        },
+                    ),
+                ])),
+                orders: BTreeMap::from_iter(IntoIter::new([
+                    (1, free_weth_order.clone()),
+                    (2, free_weth_order),
+                ])),
+                ..Default::default()
+            },
+            &test_context(),
+        )
         .await
         .unwrap();
 
@@ -0,0 +1,157 @@
+//! Classification of the failures of aggregator queries.
+//!
+//! The errors of the 0x and Paraswap apis are mapped to a few categories, and each category
+//! has a policy: either only the affected order is left out, or the results of the whole batch
+//! of queries are discarded, as the aggregator can not be trusted for any of them.
+//!
+//! The policy applies to orders quoted on 0x. Left-over amounts all need to be traded, so any
+//! failure discards their batch, while a failing Paraswap query never affects the cows found
+//! for the other orders and only drops its order.
+
+use crate::solve::paraswap_solver::api::ParaswapResponseError;
+use crate::solve::zeroex_solver::api::ZeroExResponseError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AggregatorError {
+    #[error("the quoted price violates the limit price")]
+    LimitPriceViolated,
+
+    #[error("not enough liquidity for the trade")]
+    NoLiquidity,
+
+    #[error("the request timed out")]
+    Timeout,
+
+    // Connectivity or non-response error
+    #[error("transport error: {0}")]
+    Transport(String),
+
+    // Error reported by the aggregator, or a response that could not be understood
+    #[error("server error: {0}")]
+    ServerError(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FailurePolicy {
+    DropOrder,
+    AbortBatch,
+}
+
+impl AggregatorError {
+    pub fn policy(&self) -> FailurePolicy {
+        match self {
+            AggregatorError::LimitPriceViolated
+            | AggregatorError::NoLiquidity
+            | AggregatorError::Timeout => FailurePolicy::DropOrder,
+            AggregatorError::Transport(_) | AggregatorError::ServerError(_) => {
+                FailurePolicy::AbortBatch
+            }
+        }
+    }
+
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        match err.is_timeout() {
+            true => AggregatorError::Timeout,
+            false => AggregatorError::Transport(err.to_string()),
+        }
+    }
+}
+
+impl From<ZeroExResponseError> for AggregatorError {
+    fn from(err: ZeroExResponseError) -> Self {
+        match err {
+            ZeroExResponseError::InsufficientLiquidity => AggregatorError::NoLiquidity,
+            ZeroExResponseError::Send(err) | ZeroExResponseError::TextFetch(err) => {
+                AggregatorError::from_reqwest(err)
+            }
+            ZeroExResponseError::ServerError(_)
+            | ZeroExResponseError::UnknownZeroExError(_)
+            | ZeroExResponseError::DeserializeError(..) => {
+                AggregatorError::ServerError(err.to_string())
+            }
+        }
+    }
+}
+
+impl From<ParaswapResponseError> for AggregatorError {
+    fn from(err: ParaswapResponseError) -> Self {
+        match err {
+            ParaswapResponseError::InsufficientLiquidity => AggregatorError::NoLiquidity,
+            // Only returned once retrying did not help, i.e. the price moved against the order.
+            ParaswapResponseError::PriceChange | ParaswapResponseError::TooMuchSlippageOnQuote => {
+                AggregatorError::LimitPriceViolated
+            }
+            ParaswapResponseError::Send(err) | ParaswapResponseError::TextFetch(err) => {
+                AggregatorError::from_reqwest(err)
+            }
+            ParaswapResponseError::ComputePrice(_)
+            | ParaswapResponseError::BuildingTransaction(_)
+            | ParaswapResponseError::GetParaswapPool(_)
+            | ParaswapResponseError::UnknownParaswapError(_)
+            | ParaswapResponseError::DeserializeError(_) => {
+                AggregatorError::ServerError(err.to_string())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solve::paraswap_solver::api::{parse_price_response_text, Root};
+    use crate::solve::zeroex_solver::api::parse_zeroex_response_text;
+
+    #[test]
+    fn zeroex_errors_are_classified() {
+        let err = AggregatorError::from(ZeroExResponseError::ServerError("query".into()));
+        assert!(matches!(err, AggregatorError::ServerError(_)));
+        assert_eq!(err.policy(), FailurePolicy::AbortBatch);
+
+        let deserialize_error = serde_json::from_str::<u32>("").unwrap_err();
+        let err = AggregatorError::from(ZeroExResponseError::DeserializeError(
+            deserialize_error,
+            String::new(),
+        ));
+        assert_eq!(err.policy(), FailurePolicy::AbortBatch);
+
+        let response = r#"{
+            "code": 100,
+            "reason": "Validation Failed",
+            "validationErrors": [
+                {"field": "buyAmount", "code": 1004, "reason": "INSUFFICIENT_ASSET_LIQUIDITY"}
+            ]
+        }"#;
+        let err = AggregatorError::from(parse_zeroex_response_text(response, "query").unwrap_err());
+        assert!(matches!(err, AggregatorError::NoLiquidity));
+        assert_eq!(err.policy(), FailurePolicy::DropOrder);
+    }
+
+    #[test]
+    fn paraswap_errors_are_classified() {
+        let response = r#"{"error": "No routes found with enough liquidity"}"#;
+        let err = AggregatorError::from(
+            parse_price_response_text::<Root>(response, "query").unwrap_err(),
+        );
+        assert!(matches!(err, AggregatorError::NoLiquidity));
+        assert_eq!(err.policy(), FailurePolicy::DropOrder);
+
+        let err = AggregatorError::from(ParaswapResponseError::PriceChange);
+        assert!(matches!(err, AggregatorError::LimitPriceViolated));
+        assert_eq!(err.policy(), FailurePolicy::DropOrder);
+
+        let err =
+            AggregatorError::from(ParaswapResponseError::UnknownParaswapError("error".into()));
+        assert!(matches!(err, AggregatorError::ServerError(_)));
+        assert_eq!(err.policy(), FailurePolicy::AbortBatch);
+    }
+
+    #[test]
+    fn only_order_specific_failures_drop_the_order() {
+        assert_eq!(AggregatorError::Timeout.policy(), FailurePolicy::DropOrder);
+        assert_eq!(
+            AggregatorError::Transport("connection refused".into()).policy(),
+            FailurePolicy::AbortBatch
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! Module routing trades through the AMMs, which are supplied by the driver in the instance.
+//!
+//! In contrast to the aggregator based solvers, no external API is needed: swap amounts are
+//! computed locally from the pool reserves and the driver encodes the resulting executions.
+
+pub mod constant_product;
+mod fixed_point;
+mod log_exp_math;
+pub mod stable;
+pub mod weighted_product;
+
+use crate::models::batch_auction_model::{
+    AmmModel, AmmParameters, TokenInfoModel, WeightedPoolTokenData,
+};
+use primitive_types::{H160, U256};
+use std::collections::BTreeMap;
+use weighted_product::WeightedToken;
+
+/// A single swap against one of the AMMs of the instance.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmmSwap {
+    pub amm_id: usize,
+    pub sell_token: H160,
+    pub buy_token: H160,
+    pub sell_amount: U256,
+    pub buy_amount: U256,
+}
+
+/// Swaps trading the sell token of the first swap into the buy token of the last swap.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AmmRoute {
+    pub swaps: Vec<AmmSwap>,
+}
+
+impl AmmRoute {
+    pub fn sell_amount(&self) -> U256 {
+        self.swaps
+            .first()
+            .map(|swap| swap.sell_amount)
+            .unwrap_or_default()
+    }
+
+    pub fn buy_amount(&self) -> U256 {
+        self.swaps
+            .last()
+            .map(|swap| swap.buy_amount)
+            .unwrap_or_default()
+    }
+}
+
+/// Routes trades through the AMMs of the instance, either directly or via one intermediate
+/// token. The router keeps track of the reserves, so that later trades are priced against
+/// the pool states resulting from earlier trades.
+#[derive(Clone, Debug, Default)]
+pub struct AmmRouter {
+    amms: BTreeMap<usize, AmmModel>,
+    /// Token decimals, which are needed by the Balancer pools for scaling amounts.
+    decimals: BTreeMap<H160, u8>,
+}
+
+impl AmmRouter {
+    pub fn new(amms: BTreeMap<usize, AmmModel>, tokens: &BTreeMap<H160, TokenInfoModel>) -> Self {
+        Self {
+            amms: amms
+                .into_iter()
+                .filter(|(_, amm)| amm.has_sufficient_reserves())
+                .collect(),
+            decimals: tokens
+                .iter()
+                .filter_map(|(token, info)| Some((*token, info.decimals?)))
+                .collect(),
+        }
+    }
+
+    /// Finds the route with the highest buy amount for selling exactly `sell_amount`.
+    pub fn quote_sell(
+        &self,
+        sell_token: H160,
+        buy_token: H160,
+        sell_amount: U256,
+    ) -> Option<AmmRoute> {
+        self.candidate_paths(sell_token, buy_token)
+            .into_iter()
+            .filter_map(|path| self.route_for_sell_amount(&path, sell_amount))
+            .max_by_key(|route| route.buy_amount())
+    }
+
+    /// Finds the route with the lowest sell amount for buying exactly `buy_amount`.
+    pub fn quote_buy(
+        &self,
+        sell_token: H160,
+        buy_token: H160,
+        buy_amount: U256,
+    ) -> Option<AmmRoute> {
+        self.candidate_paths(sell_token, buy_token)
+            .into_iter()
+            .filter_map(|path| self.route_for_buy_amount(&path, buy_amount))
+            .min_by_key(|route| route.sell_amount())
+    }
+
+    /// Updates the reserves of all AMMs used by the route.
+    pub fn execute(&mut self, route: &AmmRoute) {
+        for swap in &route.swaps {
+            if let Some(amm) = self.amms.get_mut(&swap.amm_id) {
+                update_reserves(amm, swap);
+            }
+        }
+    }
+
+    /// All direct paths and all paths via one intermediate token, each given as a list of
+    /// (amm id, sell token, buy token) hops.
+    fn candidate_paths(&self, sell_token: H160, buy_token: H160) -> Vec<Vec<(usize, H160, H160)>> {
+        if sell_token == buy_token {
+            return Vec::new();
+        }
+        let amms_with_sell_token: Vec<(usize, Vec<H160>)> = self
+            .amms
+            .iter()
+            .map(|(id, amm)| (*id, tokens(amm)))
+            .filter(|(_, tokens)| tokens.contains(&sell_token))
+            .collect();
+        let amms_with_buy_token: Vec<(usize, Vec<H160>)> = self
+            .amms
+            .iter()
+            .map(|(id, amm)| (*id, tokens(amm)))
+            .filter(|(_, tokens)| tokens.contains(&buy_token))
+            .collect();
+
+        let mut paths = Vec::new();
+        for (id, tokens) in &amms_with_sell_token {
+            if tokens.contains(&buy_token) {
+                paths.push(vec![(*id, sell_token, buy_token)]);
+            }
+        }
+        for (first_id, first_tokens) in &amms_with_sell_token {
+            for (second_id, second_tokens) in &amms_with_buy_token {
+                if first_id == second_id {
+                    continue;
+                }
+                for intermediate_token in first_tokens {
+                    if *intermediate_token == sell_token
+                        || *intermediate_token == buy_token
+                        || !second_tokens.contains(intermediate_token)
+                    {
+                        continue;
+                    }
+                    paths.push(vec![
+                        (*first_id, sell_token, *intermediate_token),
+                        (*second_id, *intermediate_token, buy_token),
+                    ]);
+                }
+            }
+        }
+        paths
+    }
+
+    fn route_for_sell_amount(
+        &self,
+        path: &[(usize, H160, H160)],
+        sell_amount: U256,
+    ) -> Option<AmmRoute> {
+        let mut swaps = Vec::with_capacity(path.len());
+        let mut amount_in = sell_amount;
+        for (amm_id, sell_token, buy_token) in path {
+            let amount_out = get_amount_out(
+                self.amms.get(amm_id)?,
+                &self.decimals,
+                *sell_token,
+                *buy_token,
+                amount_in,
+            )?;
+            swaps.push(AmmSwap {
+                amm_id: *amm_id,
+                sell_token: *sell_token,
+                buy_token: *buy_token,
+                sell_amount: amount_in,
+                buy_amount: amount_out,
+            });
+            amount_in = amount_out;
+        }
+        Some(AmmRoute { swaps })
+    }
+
+    fn route_for_buy_amount(
+        &self,
+        path: &[(usize, H160, H160)],
+        buy_amount: U256,
+    ) -> Option<AmmRoute> {
+        let mut swaps = Vec::with_capacity(path.len());
+        let mut amount_out = buy_amount;
+        for (amm_id, sell_token, buy_token) in path.iter().rev() {
+            let amount_in = get_amount_in(
+                self.amms.get(amm_id)?,
+                &self.decimals,
+                *sell_token,
+                *buy_token,
+                amount_out,
+            )?;
+            swaps.push(AmmSwap {
+                amm_id: *amm_id,
+                sell_token: *sell_token,
+                buy_token: *buy_token,
+                sell_amount: amount_in,
+                buy_amount: amount_out,
+            });
+            amount_out = amount_in;
+        }
+        swaps.reverse();
+        Some(AmmRoute { swaps })
+    }
+}
+
+fn tokens(amm: &AmmModel) -> Vec<H160> {
+    match &amm.parameters {
+        AmmParameters::ConstantProduct(parameters) => parameters.reserves.keys().copied().collect(),
+        AmmParameters::WeightedProduct(parameters) => parameters.reserves.keys().copied().collect(),
+        AmmParameters::Stable(parameters) => parameters.reserves.keys().copied().collect(),
+    }
+}
+
+/// Amount of `buy_token` received for selling `sell_amount` into the AMM.
+fn get_amount_out(
+    amm: &AmmModel,
+    decimals: &BTreeMap<H160, u8>,
+    sell_token: H160,
+    buy_token: H160,
+    sell_amount: U256,
+) -> Option<U256> {
+    match &amm.parameters {
+        AmmParameters::ConstantProduct(parameters) => constant_product::get_amount_out(
+            *parameters.reserves.get(&sell_token)?,
+            *parameters.reserves.get(&buy_token)?,
+            sell_amount,
+            &amm.fee,
+        ),
+        AmmParameters::WeightedProduct(parameters) => weighted_product::get_amount_out(
+            &weighted_token(&parameters.reserves, decimals, sell_token)?,
+            &weighted_token(&parameters.reserves, decimals, buy_token)?,
+            sell_amount,
+            &amm.fee,
+        ),
+        AmmParameters::Stable(parameters) => {
+            stable::get_amount_out(parameters, sell_token, buy_token, sell_amount, &amm.fee)
+        }
+    }
+}
+
+/// Amount of `sell_token` needed to receive `buy_amount` from the AMM.
+fn get_amount_in(
+    amm: &AmmModel,
+    decimals: &BTreeMap<H160, u8>,
+    sell_token: H160,
+    buy_token: H160,
+    buy_amount: U256,
+) -> Option<U256> {
+    match &amm.parameters {
+        AmmParameters::ConstantProduct(parameters) => constant_product::get_amount_in(
+            *parameters.reserves.get(&sell_token)?,
+            *parameters.reserves.get(&buy_token)?,
+            buy_amount,
+            &amm.fee,
+        ),
+        AmmParameters::WeightedProduct(parameters) => weighted_product::get_amount_in(
+            &weighted_token(&parameters.reserves, decimals, sell_token)?,
+            &weighted_token(&parameters.reserves, decimals, buy_token)?,
+            buy_amount,
+            &amm.fee,
+        ),
+        AmmParameters::Stable(parameters) => {
+            stable::get_amount_in(parameters, sell_token, buy_token, buy_amount, &amm.fee)
+        }
+    }
+}
+
+fn weighted_token<'a>(
+    reserves: &'a BTreeMap<H160, WeightedPoolTokenData>,
+    decimals: &BTreeMap<H160, u8>,
+    token: H160,
+) -> Option<WeightedToken<'a>> {
+    let data = reserves.get(&token)?;
+    Some(WeightedToken {
+        balance: data.balance,
+        weight: &data.weight,
+        decimals: *decimals.get(&token)?,
+    })
+}
+
+fn update_reserves(amm: &mut AmmModel, swap: &AmmSwap) {
+    match &mut amm.parameters {
+        AmmParameters::ConstantProduct(parameters) => {
+            update_reserve(&mut parameters.reserves, swap);
+        }
+        AmmParameters::WeightedProduct(parameters) => {
+            if let Some(data) = parameters.reserves.get_mut(&swap.sell_token) {
+                data.balance = data.balance.saturating_add(swap.sell_amount);
+            }
+            if let Some(data) = parameters.reserves.get_mut(&swap.buy_token) {
+                data.balance = data.balance.saturating_sub(swap.buy_amount);
+            }
+        }
+        AmmParameters::Stable(parameters) => {
+            update_reserve(&mut parameters.reserves, swap);
+        }
+    }
+}
+
+fn update_reserve(reserves: &mut BTreeMap<H160, U256>, swap: &AmmSwap) {
+    if let Some(balance) = reserves.get_mut(&swap.sell_token) {
+        *balance = balance.saturating_add(swap.sell_amount);
+    }
+    if let Some(balance) = reserves.get_mut(&swap.buy_token) {
+        *balance = balance.saturating_sub(swap.buy_amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::batch_auction_model::{ConstantProductPoolParameters, CostModel};
+    use maplit::btreemap;
+    use num::BigRational;
+
+    fn constant_product_pool(reserves: BTreeMap<H160, U256>) -> AmmModel {
+        AmmModel {
+            parameters: AmmParameters::ConstantProduct(ConstantProductPoolParameters { reserves }),
+            fee: BigRational::new(3.into(), 1000.into()),
+            cost: CostModel::default(),
+            mandatory: false,
+        }
+    }
+
+    #[test]
+    fn routes_through_best_path() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let router = AmmRouter::new(
+            btreemap! {
+            // shallow direct pool
+            0 => constant_product_pool(btreemap! { token_a => 1_000.into(), token_b => 1_000.into() }),
+            // deep pools via token c
+            1 => constant_product_pool(btreemap! { token_a => 1_000_000.into(), token_c => 1_000_000.into() }),
+            2 => constant_product_pool(btreemap! { token_c => 1_000_000.into(), token_b => 1_000_000.into() }),
+            },
+            &BTreeMap::new(),
+        );
+
+        let route = router.quote_sell(token_a, token_b, 100.into()).unwrap();
+        assert_eq!(route.swaps.len(), 2);
+        assert_eq!(route.swaps[0].amm_id, 1);
+        assert_eq!(route.swaps[1].amm_id, 2);
+        assert_eq!(route.sell_amount(), 100.into());
+        assert_eq!(route.swaps[0].buy_amount, route.swaps[1].sell_amount);
+
+        let route = router.quote_buy(token_a, token_b, 98.into()).unwrap();
+        assert_eq!(route.swaps.len(), 2);
+        assert_eq!(route.buy_amount(), 98.into());
+        assert_eq!(route.swaps[0].buy_amount, route.swaps[1].sell_amount);
+
+        assert!(router
+            .quote_sell(token_a, H160::from_low_u64_be(4), 100.into())
+            .is_none());
+    }
+
+    #[test]
+    fn executed_routes_update_reserves() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let mut router = AmmRouter::new(
+            btreemap! {
+            0 => constant_product_pool(btreemap! { token_a => 1_000.into(), token_b => 1_000.into() }),
+            },
+            &BTreeMap::new(),
+        );
+        let first = router.quote_sell(token_a, token_b, 100.into()).unwrap();
+        assert_eq!(first.buy_amount(), 90.into());
+        router.execute(&first);
+        match &router.amms[&0].parameters {
+            AmmParameters::ConstantProduct(parameters) => {
+                assert_eq!(parameters.reserves[&token_a], 1_100.into());
+                assert_eq!(parameters.reserves[&token_b], 910.into());
+            }
+            _ => unreachable!(),
+        }
+        // The second trade gets a worse price
+        let second = router.quote_sell(token_a, token_b, 100.into()).unwrap();
+        assert!(second.buy_amount() < first.buy_amount());
+    }
+
+    #[test]
+    fn ignores_pools_without_sufficient_reserves() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let router = AmmRouter::new(
+            btreemap! {
+            0 => constant_product_pool(btreemap! { token_a => 1_000.into(), token_b => 0.into() }),
+            },
+            &BTreeMap::new(),
+        );
+        assert!(router.amms.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+//! Swap math of Uniswap V2 like constant product pools.
+//!
+//! The rounding matches the Uniswap V2 router: output amounts are rounded down and
+//! input amounts are rounded down and then increased by one.
+
+use crate::utils::conversions::{big_int_to_u256, U256Ext};
+use num::{BigRational, One, Signed};
+use primitive_types::U256;
+
+/// Returns the amount received when selling `amount_in` into the pool.
+pub fn get_amount_out(
+    reserve_in: U256,
+    reserve_out: U256,
+    amount_in: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() || !valid_fee(fee) {
+        return None;
+    }
+    let amount_in_with_fee = amount_in.to_big_rational() * (BigRational::one() - fee);
+    let numerator = amount_in_with_fee.clone() * reserve_out.to_big_rational();
+    let denominator = reserve_in.to_big_rational() + amount_in_with_fee;
+    big_int_to_u256(&(numerator / denominator).floor().to_integer()).ok()
+}
+
+/// Returns the amount that needs to be sold into the pool in order to receive `amount_out`.
+pub fn get_amount_in(
+    reserve_in: U256,
+    reserve_out: U256,
+    amount_out: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_out.is_zero() || reserve_in.is_zero() || amount_out >= reserve_out || !valid_fee(fee)
+    {
+        return None;
+    }
+    let numerator = reserve_in.to_big_rational() * amount_out.to_big_rational();
+    let denominator = (reserve_out - amount_out).to_big_rational() * (BigRational::one() - fee);
+    let amount_in = big_int_to_u256(&(numerator / denominator).floor().to_integer()).ok()?;
+    amount_in.checked_add(U256::one())
+}
+
+fn valid_fee(fee: &BigRational) -> bool {
+    !fee.is_negative() && fee < &BigRational::one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uniswap_fee() -> BigRational {
+        BigRational::new(3.into(), 1000.into())
+    }
+
+    #[test]
+    fn amount_out_matches_uniswap_router() {
+        // 100 * 997 * 1000 / (1000 * 1000 + 100 * 997) = 90.66...
+        assert_eq!(
+            get_amount_out(1000.into(), 1000.into(), 100.into(), &uniswap_fee()),
+            Some(90.into())
+        );
+        assert_eq!(
+            get_amount_out(1000.into(), 1000.into(), 0.into(), &uniswap_fee()),
+            None
+        );
+        assert_eq!(
+            get_amount_out(0.into(), 1000.into(), 100.into(), &uniswap_fee()),
+            None
+        );
+    }
+
+    #[test]
+    fn amount_in_matches_uniswap_router() {
+        // 1000 * 90 * 1000 / (910 * 997) = 99.19..., rounded down and plus one
+        assert_eq!(
+            get_amount_in(1000.into(), 1000.into(), 90.into(), &uniswap_fee()),
+            Some(100.into())
+        );
+        // Can not buy the complete reserves
+        assert_eq!(
+            get_amount_in(1000.into(), 1000.into(), 1000.into(), &uniswap_fee()),
+            None
+        );
+    }
+
+    #[test]
+    fn amount_in_buys_at_least_amount_out() {
+        let reserve_in = U256::from(1_234_567_890_123u64);
+        let reserve_out = U256::from(987_654_321_987u64);
+        for amount_out in [1u64, 1_000, 1_000_000, 100_000_000_000] {
+            let amount_in =
+                get_amount_in(reserve_in, reserve_out, amount_out.into(), &uniswap_fee()).unwrap();
+            let received = get_amount_out(reserve_in, reserve_out, amount_in, &uniswap_fee());
+            assert!(received.unwrap() >= amount_out.into());
+        }
+    }
+
+    #[test]
+    fn invalid_fee_is_rejected() {
+        let fee = BigRational::one();
+        assert_eq!(
+            get_amount_out(1000.into(), 1000.into(), 100.into(), &fee),
+            None
+        );
+        assert_eq!(
+            get_amount_in(1000.into(), 1000.into(), 100.into(), &fee),
+            None
+        );
+    }
+}
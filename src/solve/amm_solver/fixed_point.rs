@@ -0,0 +1,252 @@
+//! 18 decimal fixed point arithmetic with the rounding of Balancer's `FixedPoint` library.
+//!
+//! Values are backed by a `BigInt`, so that intermediate results can not overflow. Operations
+//! which would revert on-chain (subtraction underflow, division by zero, out of bound powers)
+//! return `None` instead.
+
+use super::log_exp_math;
+use crate::utils::conversions::{big_int_to_u256, U256Ext};
+use num::{BigInt, BigRational, Integer, One, Signed, Zero};
+use primitive_types::U256;
+
+const DECIMALS: u32 = 18;
+/// Relative error Balancer adds to the result of `LogExpMath.pow` in `powUp`, 1e-14.
+const MAX_POW_RELATIVE_ERROR: u64 = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bfp(BigInt);
+
+impl Bfp {
+    pub fn zero() -> Self {
+        Self(BigInt::zero())
+    }
+
+    pub fn one() -> Self {
+        Self(BigInt::from(10u64).pow(DECIMALS))
+    }
+
+    /// Interprets the amount as a raw fixed point value, i.e. `1e18` is one.
+    pub fn from_wei(amount: U256) -> Self {
+        Self(amount.to_big_int())
+    }
+
+    /// Converts a ratio into a fixed point value, rounding down.
+    pub fn from_big_rational(ratio: &BigRational) -> Option<Self> {
+        if ratio.is_negative() {
+            return None;
+        }
+        Some(Self(
+            (ratio * BigRational::from_integer(Self::one().0))
+                .floor()
+                .to_integer(),
+        ))
+    }
+
+    /// Scales a token amount up to 18 decimals.
+    pub fn upscale(amount: U256, scaling_factor: U256) -> Self {
+        Self(amount.to_big_int() * scaling_factor.to_big_int())
+    }
+
+    /// Scales an 18 decimals value down to a token amount, rounding down.
+    pub fn downscale_down(&self, scaling_factor: U256) -> Option<U256> {
+        if scaling_factor.is_zero() {
+            return None;
+        }
+        big_int_to_u256(&self.0.div_floor(&scaling_factor.to_big_int())).ok()
+    }
+
+    /// Scales an 18 decimals value down to a token amount, rounding up.
+    pub fn downscale_up(&self, scaling_factor: U256) -> Option<U256> {
+        if scaling_factor.is_zero() {
+            return None;
+        }
+        big_int_to_u256(&self.0.div_ceil(&scaling_factor.to_big_int())).ok()
+    }
+
+    pub fn as_u256(&self) -> Option<U256> {
+        big_int_to_u256(&self.0).ok()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        Self(&self.0 + &other.0)
+    }
+
+    pub fn sub(&self, other: &Self) -> Option<Self> {
+        if other.0 > self.0 {
+            return None;
+        }
+        Some(Self(&self.0 - &other.0))
+    }
+
+    pub fn mul_down(&self, other: &Self) -> Self {
+        Self((&self.0 * &other.0) / Self::one().0)
+    }
+
+    pub fn mul_up(&self, other: &Self) -> Self {
+        let product = &self.0 * &other.0;
+        if product.is_zero() {
+            return Self::zero();
+        }
+        Self((product - 1) / Self::one().0 + 1)
+    }
+
+    pub fn div_down(&self, other: &Self) -> Option<Self> {
+        if other.is_zero() {
+            return None;
+        }
+        Some(Self((&self.0 * Self::one().0) / &other.0))
+    }
+
+    pub fn div_up(&self, other: &Self) -> Option<Self> {
+        if other.is_zero() {
+            return None;
+        }
+        if self.is_zero() {
+            return Some(Self::zero());
+        }
+        Some(Self((&self.0 * Self::one().0 - 1) / &other.0 + 1))
+    }
+
+    /// `1 - x`, saturating at zero.
+    pub fn complement(&self) -> Self {
+        Self::one().sub(self).unwrap_or_else(Self::zero)
+    }
+
+    /// `x^y`, rounded up like `FixedPoint.powUp`.
+    ///
+    /// The exponents one, two and four are computed with exact multiplications. Other powers
+    /// are computed with `LogExpMath.pow`, and then increased by its maximal relative error.
+    pub fn pow_up(&self, exponent: &Self) -> Option<Self> {
+        let one = Self::one();
+        if *exponent == one {
+            return Some(self.clone());
+        }
+        if exponent.0 == &one.0 * 2 {
+            return Some(self.mul_up(self));
+        }
+        if exponent.0 == &one.0 * 4 {
+            let square = self.mul_up(self);
+            return Some(square.mul_up(&square));
+        }
+        let raw = Self(log_exp_math::pow(&self.0, &exponent.0)?);
+        let max_error = raw
+            .mul_up(&Self(BigInt::from(MAX_POW_RELATIVE_ERROR)))
+            .add(&Self(BigInt::one()));
+        Some(raw.add(&max_error))
+    }
+}
+
+/// The factor scaling amounts of a token with the given decimals to 18 decimals.
+pub fn scaling_factor(decimals: u8) -> Option<U256> {
+    let exponent = DECIMALS.checked_sub(decimals.into())?;
+    Some(U256::exp10(exponent as usize))
+}
+
+/// Deducts the swap fee from an amount in, like Balancer's `_subtractSwapFeeAmount`.
+pub fn subtract_swap_fee_amount(amount: U256, fee: &BigRational) -> Option<U256> {
+    let amount = Bfp::from_wei(amount);
+    let fee_amount = amount.mul_up(&Bfp::from_big_rational(fee)?);
+    amount.sub(&fee_amount)?.as_u256()
+}
+
+/// Adds the swap fee to an amount in, like Balancer's `_addSwapFeeAmount`.
+pub fn add_swap_fee_amount(amount: U256, fee: &BigRational) -> Option<U256> {
+    let fee_complement = Bfp::from_big_rational(fee)?.complement();
+    Bfp::from_wei(amount).div_up(&fee_complement)?.as_u256()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bfp(value: &str) -> Bfp {
+        Bfp(value.parse().unwrap())
+    }
+
+    #[test]
+    fn rounding_of_multiplication_and_division() {
+        let third = bfp("333333333333333333");
+        assert_eq!(third.mul_down(&bfp("2")), bfp("0"));
+        assert_eq!(third.mul_up(&bfp("2")), bfp("1"));
+        assert_eq!(
+            Bfp::one().div_down(&bfp("3000000000000000000")),
+            Some(third)
+        );
+        assert_eq!(
+            Bfp::one().div_up(&bfp("3000000000000000000")),
+            Some(bfp("333333333333333334"))
+        );
+        assert_eq!(Bfp::one().div_up(&Bfp::zero()), None);
+        assert_eq!(Bfp::zero().sub(&Bfp::one()), None);
+        assert_eq!(bfp("2000000000000000000").complement(), Bfp::zero());
+    }
+
+    #[test]
+    fn pow_up_adds_the_maximal_error_of_balancer() {
+        // LogExpMath.pow(0.5, 0.5) is 0.707106781186547525
+        assert_eq!(
+            bfp("500000000000000000")
+                .pow_up(&bfp("500000000000000000"))
+                .unwrap(),
+            bfp("707106781186554598")
+        );
+        // LogExpMath.pow(1.1, 3) is 1.330999999999999995, below the exact power
+        assert_eq!(
+            bfp("1100000000000000000")
+                .pow_up(&bfp("3000000000000000000"))
+                .unwrap(),
+            bfp("1331000000000013306")
+        );
+        // Exact multiplications for the special exponents
+        assert_eq!(
+            bfp("1500000000000000000")
+                .pow_up(&bfp("2000000000000000000"))
+                .unwrap(),
+            bfp("2250000000000000000")
+        );
+        assert_eq!(
+            bfp("1050000000000000000")
+                .pow_up(&bfp("4000000000000000000"))
+                .unwrap(),
+            bfp("1215506250000000000")
+        );
+        assert_eq!(
+            bfp("1500000000000000000").pow_up(&Bfp::one()).unwrap(),
+            bfp("1500000000000000000")
+        );
+    }
+
+    #[test]
+    fn pow_respects_exponent_bounds() {
+        assert!(bfp("1000000000000000000000")
+            .pow_up(&bfp("50000000000000000000"))
+            .is_none());
+    }
+
+    #[test]
+    fn scaling_rounds_in_the_given_direction() {
+        let scaling_factor = scaling_factor(6).unwrap();
+        let scaled = Bfp::upscale(1234.into(), scaling_factor);
+        assert_eq!(scaled, bfp("1234000000000000"));
+        let value = bfp("1234000000000001");
+        assert_eq!(value.downscale_down(scaling_factor), Some(1234.into()));
+        assert_eq!(value.downscale_up(scaling_factor), Some(1235.into()));
+        assert_eq!(super::scaling_factor(19), None);
+    }
+
+    #[test]
+    fn swap_fees_are_rounded_in_favour_of_the_pool() {
+        let fee = BigRational::new(3.into(), 1000.into());
+        // 0.3% of 1001 is 3.003, which is rounded up to 4
+        assert_eq!(
+            subtract_swap_fee_amount(1001.into(), &fee),
+            Some(997.into())
+        );
+        // 1000 / 0.997 = 1003.009...
+        assert_eq!(add_swap_fee_amount(1000.into(), &fee), Some(1004.into()));
+    }
+}
@@ -0,0 +1,246 @@
+//! Port of Balancer's `LogExpMath` library, on which `FixedPoint.powUp` is based.
+//!
+//! Every step follows the Solidity implementation, including its integer divisions which
+//! truncate towards zero, so that the results are identical to the on-chain ones. Arguments and
+//! results have 18 decimals, intermediate values 20 or 36 decimals. Arguments for which the
+//! library reverts return `None`.
+
+use num::{BigInt, Signed, Zero};
+
+/// Bounds of the natural exponent accepted by `exp`, with 18 decimals.
+const MIN_NATURAL_EXPONENT: i64 = -41;
+const MAX_NATURAL_EXPONENT: i64 = 130;
+
+/// `x0 = 2^7` with 18 decimals, and `a0 = e^x0` without decimals.
+const X0: u128 = 128_000_000_000_000_000_000;
+/// `a0` divided by `10^A0_EXPONENT`, as it does not fit into a `u128`.
+const A0_MANTISSA: u128 = 388_770_840_599_459_509_222;
+const A0_EXPONENT: u32 = 35;
+/// `x1 = 2^6` with 18 decimals, and `a1 = e^x1` without decimals.
+const X1: u128 = 64_000_000_000_000_000_000;
+const A1: u128 = 6_235_149_080_811_616_882_910_000_000;
+/// `x_n = 2^(7 - n)` and `a_n = e^x_n` for n from 2 to 11, both with 20 decimals.
+const EXPONENTIALS: [(u128, u128); 10] = [
+    (
+        3_200_000_000_000_000_000_000,
+        7_896_296_018_268_069_516_100_000_000_000_000,
+    ),
+    (
+        1_600_000_000_000_000_000_000,
+        888_611_052_050_787_263_676_000_000,
+    ),
+    (800_000_000_000_000_000_000, 298_095_798_704_172_827_474_000),
+    (400_000_000_000_000_000_000, 5_459_815_003_314_423_907_810),
+    (200_000_000_000_000_000_000, 738_905_609_893_065_022_723),
+    (100_000_000_000_000_000_000, 271_828_182_845_904_523_536),
+    (50_000_000_000_000_000_000, 164_872_127_070_012_814_685),
+    (25_000_000_000_000_000_000, 128_402_541_668_774_148_407),
+    (12_500_000_000_000_000_000, 113_314_845_306_682_631_683),
+    (6_250_000_000_000_000_000, 106_449_445_891_785_942_956),
+];
+/// `exp` only needs the exponentials up to `x9`.
+const EXP_EXPONENTIALS: usize = 8;
+
+fn ten_to(exponent: u32) -> BigInt {
+    BigInt::from(10u64).pow(exponent)
+}
+
+/// `x^y`, like `LogExpMath.pow`.
+pub fn pow(x: &BigInt, y: &BigInt) -> Option<BigInt> {
+    let one_18 = ten_to(18);
+    if y.is_zero() {
+        return Some(one_18);
+    }
+    if x.is_zero() {
+        return Some(BigInt::zero());
+    }
+    let mild_exponent_bound = (BigInt::from(1) << 254usize) / ten_to(20);
+    if x.is_negative() || x.bits() > 255 || y.is_negative() || *y >= mild_exponent_bound {
+        return None;
+    }
+
+    let ln_36_lower_bound = &one_18 - ten_to(17);
+    let ln_36_upper_bound = &one_18 + ten_to(17);
+    let logx_times_y = if ln_36_lower_bound < *x && *x < ln_36_upper_bound {
+        let ln_36_x = ln_36(x);
+        (&ln_36_x / &one_18) * y + ((&ln_36_x % &one_18) * y) / &one_18
+    } else {
+        ln(x) * y
+    } / &one_18;
+
+    if logx_times_y < BigInt::from(MIN_NATURAL_EXPONENT) * &one_18
+        || logx_times_y > BigInt::from(MAX_NATURAL_EXPONENT) * &one_18
+    {
+        return None;
+    }
+    exp(logx_times_y)
+}
+
+/// `e^x`, like `LogExpMath.exp`.
+fn exp(mut x: BigInt) -> Option<BigInt> {
+    let one_18 = ten_to(18);
+    let one_20 = ten_to(20);
+    if x < BigInt::from(MIN_NATURAL_EXPONENT) * &one_18
+        || x > BigInt::from(MAX_NATURAL_EXPONENT) * &one_18
+    {
+        return None;
+    }
+    if x.is_negative() {
+        return Some(&one_18 * &one_18 / exp(-x)?);
+    }
+
+    let first_an = if x >= BigInt::from(X0) {
+        x -= X0;
+        BigInt::from(A0_MANTISSA) * ten_to(A0_EXPONENT)
+    } else if x >= BigInt::from(X1) {
+        x -= X1;
+        BigInt::from(A1)
+    } else {
+        BigInt::from(1)
+    };
+
+    // The remaining exponentials have 20 decimals.
+    x *= 100;
+    let mut product = one_20.clone();
+    for &(xn, an) in &EXPONENTIALS[..EXP_EXPONENTIALS] {
+        if x >= BigInt::from(xn) {
+            x -= xn;
+            product = product * an / &one_20;
+        }
+    }
+
+    // Taylor series of the small remainder, where the nth term is x^n / n!.
+    let mut series_sum = one_20.clone();
+    let mut term = x.clone();
+    series_sum += &term;
+    for n in 2..=12 {
+        term = &term * &x / &one_20 / n;
+        series_sum += &term;
+    }
+    Some(product * series_sum / &one_20 * first_an / 100)
+}
+
+/// Natural logarithm of a positive value with 18 decimals, like `LogExpMath._ln`.
+fn ln(a: &BigInt) -> BigInt {
+    let one_18 = ten_to(18);
+    let one_20 = ten_to(20);
+    if *a < one_18 {
+        return -ln(&(&one_18 * &one_18 / a));
+    }
+
+    let mut a = a.clone();
+    let mut sum = BigInt::zero();
+    let a0 = BigInt::from(A0_MANTISSA) * ten_to(A0_EXPONENT);
+    if a >= &a0 * &one_18 {
+        a /= a0;
+        sum += X0;
+    }
+    if a >= BigInt::from(A1) * &one_18 {
+        a /= A1;
+        sum += X1;
+    }
+
+    // The remaining exponentials have 20 decimals.
+    sum *= 100;
+    a *= 100;
+    for &(xn, an) in &EXPONENTIALS {
+        if a >= BigInt::from(an) {
+            a = a * &one_20 / an;
+            sum += xn;
+        }
+    }
+
+    // ln(a) = 2 * (z + z^3 / 3 + z^5 / 5 + ...) with z = (a - 1) / (a + 1)
+    let z = (&a - &one_20) * &one_20 / (&a + &one_20);
+    let z_squared = &z * &z / &one_20;
+    let mut num = z.clone();
+    let mut series_sum = z;
+    for denominator in [3, 5, 7, 9, 11] {
+        num = num * &z_squared / &one_20;
+        series_sum += &num / denominator;
+    }
+    (sum + series_sum * 2) / 100
+}
+
+/// Natural logarithm of a value with 18 decimals close to one, with 36 decimals, like
+/// `LogExpMath._ln_36`.
+fn ln_36(x: &BigInt) -> BigInt {
+    let one_36 = ten_to(36);
+    let x = x * ten_to(18);
+    let z = (&x - &one_36) * &one_36 / (&x + &one_36);
+    let z_squared = &z * &z / &one_36;
+    let mut num = z.clone();
+    let mut series_sum = z;
+    for denominator in [3, 5, 7, 9, 11, 13, 15] {
+        num = num * &z_squared / &one_36;
+        series_sum += &num / denominator;
+    }
+    series_sum * 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(value: &str) -> BigInt {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn pow_matches_the_integer_arithmetic_of_balancer() {
+        // Reference values computed with the truncating integer arithmetic of `LogExpMath`,
+        // which may be slightly below or above the exact powers.
+        for (x, y, power) in [
+            // 0.5^0.5 = 0.7071067811865475244...
+            (
+                "500000000000000000",
+                "500000000000000000",
+                "707106781186547525",
+            ),
+            // 2^0.5 = 1.4142135623730950488...
+            (
+                "2000000000000000000",
+                "500000000000000000",
+                "1414213562373095047",
+            ),
+            // 1.1^3 = 1.331
+            (
+                "1100000000000000000",
+                "3000000000000000000",
+                "1330999999999999995",
+            ),
+            // 0.95^0.25, which uses the 36 decimals logarithm
+            (
+                "950000000000000000",
+                "250000000000000000",
+                "987258544901433807",
+            ),
+            (
+                "5000000000000000000",
+                "250000000000000000",
+                "1495348781221220540",
+            ),
+        ] {
+            assert_eq!(pow(&int(x), &int(y)), Some(int(power)));
+        }
+        assert_eq!(pow(&int("123"), &BigInt::zero()), Some(ten_to(18)));
+        assert_eq!(pow(&BigInt::zero(), &int("123")), Some(BigInt::zero()));
+    }
+
+    #[test]
+    fn exponentials_and_logarithms_of_one() {
+        assert_eq!(exp(BigInt::zero()), Some(ten_to(18)));
+        assert_eq!(ln(&ten_to(18)), BigInt::zero());
+        assert_eq!(ln_36(&ten_to(18)), BigInt::zero());
+    }
+
+    #[test]
+    fn out_of_bound_arguments_are_rejected() {
+        // 1000^50 exceeds e^130
+        assert_eq!(pow(&ten_to(21), &(ten_to(18) * 50)), None);
+        assert_eq!(pow(&(BigInt::from(1) << 255usize), &ten_to(18)), None);
+        assert_eq!(pow(&ten_to(18), &(BigInt::from(1) << 254usize)), None);
+        assert_eq!(exp(ten_to(18) * 131), None);
+        assert_eq!(exp(ten_to(18) * -42), None);
+    }
+}
@@ -0,0 +1,322 @@
+//! Swap math of Balancer V2 stable pools.
+//!
+//! Balances and amounts are upscaled with the scaling rates of the pool and rounded like
+//! `StableMath` and `BaseGeneralPool`: swap fees are taken from the unscaled input amount, the
+//! invariant is rounded up and the resulting amounts are rounded in favour of the pool.
+
+use super::fixed_point::{add_swap_fee_amount, subtract_swap_fee_amount};
+use crate::models::batch_auction_model::StablePoolParameters;
+use crate::utils::conversions::{big_int_to_u256, U256Ext};
+use num::{BigInt, BigRational, Integer, Signed, Zero};
+use primitive_types::{H160, U256};
+
+/// Precision of the amplification parameter used by `StableMath`.
+const AMP_PRECISION: u64 = 1000;
+/// `StableMath` reverts if the Newton iterations don't converge within this many steps.
+const MAX_ITERATIONS: usize = 255;
+/// Maximal number of wei by which the output amount the input amount is computed for is
+/// increased, until the input amount buys the requested output amount.
+const MAX_AMOUNT_IN_ADJUSTMENTS: u64 = 10;
+
+/// Returns the amount received when selling `amount_in` into the pool.
+pub fn get_amount_out(
+    pool: &StablePoolParameters,
+    sell_token: H160,
+    buy_token: H160,
+    amount_in: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_in.is_zero() {
+        return None;
+    }
+    let mut scaled = ScaledPool::new(pool, sell_token, buy_token)?;
+    let amount_in = subtract_swap_fee_amount(amount_in, fee)?.to_big_int() * &scaled.scaling_in;
+
+    let invariant = calculate_invariant(&scaled.amplification, &scaled.balances, true)?;
+    scaled.balances[scaled.index_in] += amount_in;
+    let final_balance_out = token_balance_given_invariant_and_other_balances(
+        &scaled.amplification,
+        &scaled.balances,
+        &invariant,
+        scaled.index_out,
+    )?;
+    let amount_out: BigInt = &scaled.balances[scaled.index_out] - final_balance_out - 1;
+    if !amount_out.is_positive() {
+        return None;
+    }
+    big_int_to_u256(&amount_out.div_floor(&scaled.scaling_out))
+        .ok()
+        .filter(|amount_out| !amount_out.is_zero())
+}
+
+/// Returns the amount that needs to be sold into the pool in order to receive `amount_out`.
+///
+/// Both directions round in favour of the pool, so the input amount computed from the
+/// invariant may buy slightly less than `amount_out`. In that case, it is computed for a
+/// slightly larger output amount, until it buys at least `amount_out`.
+pub fn get_amount_in(
+    pool: &StablePoolParameters,
+    sell_token: H160,
+    buy_token: H160,
+    amount_out: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    for extra_amount_out in 0..MAX_AMOUNT_IN_ADJUSTMENTS {
+        let amount_in = estimate_amount_in(
+            pool,
+            sell_token,
+            buy_token,
+            amount_out.checked_add(extra_amount_out.into())?,
+            fee,
+        )?;
+        let received = get_amount_out(pool, sell_token, buy_token, amount_in, fee);
+        if matches!(received, Some(received) if received >= amount_out) {
+            return Some(amount_in);
+        }
+    }
+    None
+}
+
+fn estimate_amount_in(
+    pool: &StablePoolParameters,
+    sell_token: H160,
+    buy_token: H160,
+    amount_out: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_out.is_zero() {
+        return None;
+    }
+    let mut scaled = ScaledPool::new(pool, sell_token, buy_token)?;
+    let amount_out = amount_out.to_big_int() * &scaled.scaling_out;
+    if amount_out >= scaled.balances[scaled.index_out] {
+        return None;
+    }
+
+    let invariant = calculate_invariant(&scaled.amplification, &scaled.balances, true)?;
+    scaled.balances[scaled.index_out] -= amount_out;
+    let final_balance_in = token_balance_given_invariant_and_other_balances(
+        &scaled.amplification,
+        &scaled.balances,
+        &invariant,
+        scaled.index_in,
+    )?;
+    let amount_in: BigInt = final_balance_in - &scaled.balances[scaled.index_in] + 1;
+    if !amount_in.is_positive() {
+        return None;
+    }
+    add_swap_fee_amount(
+        big_int_to_u256(&amount_in.div_ceil(&scaled.scaling_in)).ok()?,
+        fee,
+    )
+}
+
+/// The upscaled state of a pool for a swap from the token at `index_in` to the one at
+/// `index_out`.
+struct ScaledPool {
+    balances: Vec<BigInt>,
+    /// The amplification parameter multiplied by `AMP_PRECISION`.
+    amplification: BigInt,
+    index_in: usize,
+    index_out: usize,
+    scaling_in: BigInt,
+    scaling_out: BigInt,
+}
+
+impl ScaledPool {
+    fn new(pool: &StablePoolParameters, sell_token: H160, buy_token: H160) -> Option<Self> {
+        if sell_token == buy_token {
+            return None;
+        }
+        let mut balances = Vec::with_capacity(pool.reserves.len());
+        let (mut index_in, mut index_out) = (None, None);
+        for (index, (token, balance)) in pool.reserves.iter().enumerate() {
+            let scaling_rate = pool.scaling_rates.get(token)?;
+            if scaling_rate.is_zero() {
+                return None;
+            }
+            balances.push(balance.to_big_int() * scaling_rate.to_big_int());
+            if *token == sell_token {
+                index_in = Some(index);
+            }
+            if *token == buy_token {
+                index_out = Some(index);
+            }
+        }
+        let amplification = (&pool.amplification_parameter
+            * BigRational::from_integer(AMP_PRECISION.into()))
+        .floor()
+        .to_integer();
+        if !amplification.is_positive() {
+            return None;
+        }
+        Some(Self {
+            balances,
+            amplification,
+            index_in: index_in?,
+            index_out: index_out?,
+            scaling_in: pool.scaling_rates[&sell_token].to_big_int(),
+            scaling_out: pool.scaling_rates[&buy_token].to_big_int(),
+        })
+    }
+}
+
+fn div(numerator: &BigInt, denominator: &BigInt, round_up: bool) -> Option<BigInt> {
+    if !denominator.is_positive() {
+        return None;
+    }
+    if round_up && !numerator.is_zero() {
+        Some((numerator - 1) / denominator + 1)
+    } else {
+        Some(numerator / denominator)
+    }
+}
+
+/// Computes the StableSwap invariant `D` with Newton's method, like
+/// `StableMath._calculateInvariant`.
+fn calculate_invariant(
+    amplification: &BigInt,
+    balances: &[BigInt],
+    round_up: bool,
+) -> Option<BigInt> {
+    let sum: BigInt = balances.iter().sum();
+    if sum.is_zero() {
+        return Some(sum);
+    }
+    let number_of_tokens = BigInt::from(balances.len());
+    let amp_precision = BigInt::from(AMP_PRECISION);
+    let amp_times_total = amplification * &number_of_tokens;
+
+    let mut invariant = sum.clone();
+    for _ in 0..MAX_ITERATIONS {
+        let mut p_d = &balances[0] * &number_of_tokens;
+        for balance in &balances[1..] {
+            p_d = div(&(p_d * balance * &number_of_tokens), &invariant, round_up)?;
+        }
+        let previous_invariant = invariant.clone();
+        let numerator = &number_of_tokens * &invariant * &invariant
+            + div(&(&amp_times_total * &sum * &p_d), &amp_precision, round_up)?;
+        let denominator = (&number_of_tokens + 1) * &invariant
+            + div(
+                &((&amp_times_total - &amp_precision) * &p_d),
+                &amp_precision,
+                !round_up,
+            )?;
+        invariant = div(&numerator, &denominator, round_up)?;
+        if (&invariant - &previous_invariant).abs() <= BigInt::from(1) {
+            return Some(invariant);
+        }
+    }
+    None
+}
+
+/// Computes the balance of the token at `token_index`, such that the pool keeps its invariant
+/// given all other balances, like `StableMath._getTokenBalanceGivenInvariantAndAllOtherBalances`.
+fn token_balance_given_invariant_and_other_balances(
+    amplification: &BigInt,
+    balances: &[BigInt],
+    invariant: &BigInt,
+    token_index: usize,
+) -> Option<BigInt> {
+    let number_of_tokens = BigInt::from(balances.len());
+    let amp_precision = BigInt::from(AMP_PRECISION);
+    let amp_times_total = amplification * &number_of_tokens;
+
+    let mut sum = balances[0].clone();
+    let mut p_d = &balances[0] * &number_of_tokens;
+    for balance in &balances[1..] {
+        p_d = div(&(p_d * balance * &number_of_tokens), invariant, false)?;
+        sum += balance;
+    }
+    sum -= &balances[token_index];
+
+    let invariant_squared = invariant * invariant;
+    let c = div(&invariant_squared, &(&amp_times_total * &p_d), true)?
+        * &amp_precision
+        * &balances[token_index];
+    let b = sum + div(invariant, &amp_times_total, false)? * &amp_precision;
+
+    let mut token_balance = div(&(&invariant_squared + &c), &(invariant + &b), true)?;
+    for _ in 0..MAX_ITERATIONS {
+        let previous_token_balance = token_balance.clone();
+        token_balance = div(
+            &(&token_balance * &token_balance + &c),
+            &(&token_balance * 2 + &b - invariant),
+            true,
+        )?;
+        if (&token_balance - &previous_token_balance).abs() <= BigInt::from(1) {
+            return Some(token_balance);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::btreemap;
+
+    fn dai_usdc_pool() -> (StablePoolParameters, H160, H160) {
+        let dai = H160::from_low_u64_be(1);
+        let usdc = H160::from_low_u64_be(2);
+        let pool = StablePoolParameters {
+            reserves: btreemap! {
+                dai => U256::exp10(24),
+                usdc => U256::exp10(12),
+            },
+            scaling_rates: btreemap! {
+                dai => U256::one(),
+                usdc => U256::exp10(12),
+            },
+            amplification_parameter: BigRational::from_integer(200.into()),
+        };
+        (pool, dai, usdc)
+    }
+
+    fn fee() -> BigRational {
+        BigRational::new(4.into(), 10_000.into())
+    }
+
+    #[test]
+    fn invariant_of_balanced_pool_is_sum_of_balances() {
+        let balances = vec![BigInt::from(10u64).pow(24), BigInt::from(10u64).pow(24)];
+        let amplification = BigInt::from(200 * AMP_PRECISION);
+        let invariant = calculate_invariant(&amplification, &balances, true).unwrap();
+        assert!((invariant - BigInt::from(2) * BigInt::from(10u64).pow(24)).abs() <= 1.into());
+    }
+
+    #[test]
+    fn stable_swap_trades_close_to_parity() {
+        let (pool, dai, usdc) = dai_usdc_pool();
+        let amount_out = get_amount_out(&pool, dai, usdc, U256::exp10(21), &fee()).unwrap();
+        // 1000 DAI minus the 0.04% fee and a small price impact
+        assert!(amount_out < U256::from(999_600_000u64));
+        assert!(amount_out > U256::from(999_500_000u64));
+
+        let amount_in = get_amount_in(&pool, usdc, dai, U256::exp10(21), &fee()).unwrap();
+        assert!(amount_in > U256::from(1_000_400_000u64));
+        assert!(amount_in < U256::from(1_000_500_000u64));
+    }
+
+    #[test]
+    fn amount_in_buys_amount_out() {
+        let (pool, dai, usdc) = dai_usdc_pool();
+        for amount_out in [1u64, 1_000, 1_000_000, 100_000_000_000] {
+            let amount_in = get_amount_in(&pool, dai, usdc, amount_out.into(), &fee()).unwrap();
+            let received = get_amount_out(&pool, dai, usdc, amount_in, &fee()).unwrap();
+            assert!(received >= amount_out.into());
+
+            let amount_in = get_amount_in(&pool, usdc, dai, amount_out.into(), &fee()).unwrap();
+            let received = get_amount_out(&pool, usdc, dai, amount_in, &fee()).unwrap();
+            assert!(received >= amount_out.into());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tokens_and_too_large_amounts() {
+        let (pool, dai, usdc) = dai_usdc_pool();
+        let unknown = H160::from_low_u64_be(3);
+        assert!(get_amount_out(&pool, dai, unknown, 1_000.into(), &fee()).is_none());
+        assert!(get_amount_in(&pool, dai, usdc, U256::exp10(12), &fee()).is_none());
+    }
+}
@@ -0,0 +1,147 @@
+//! Swap math of Balancer V2 weighted pools.
+//!
+//! Amounts are upscaled to 18 decimals and rounded like `WeightedMath` and
+//! `BaseMinimalSwapInfoPool`: swap fees are taken from the unscaled input amount, and every
+//! rounding is in favour of the pool.
+
+use super::fixed_point::{add_swap_fee_amount, scaling_factor, subtract_swap_fee_amount, Bfp};
+use num::BigRational;
+use primitive_types::U256;
+
+/// Balancer rejects swaps of more than 30% of the balances.
+const MAX_IN_RATIO: u64 = 300_000_000_000_000_000;
+const MAX_OUT_RATIO: u64 = 300_000_000_000_000_000;
+
+/// A token of a weighted pool.
+#[derive(Clone, Debug)]
+pub struct WeightedToken<'a> {
+    pub balance: U256,
+    pub weight: &'a BigRational,
+    pub decimals: u8,
+}
+
+/// Returns the amount received when selling `amount_in` into the pool.
+pub fn get_amount_out(
+    token_in: &WeightedToken,
+    token_out: &WeightedToken,
+    amount_in: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_in.is_zero() {
+        return None;
+    }
+    let (scaling_in, scaling_out) = (
+        scaling_factor(token_in.decimals)?,
+        scaling_factor(token_out.decimals)?,
+    );
+    let amount_in = Bfp::upscale(subtract_swap_fee_amount(amount_in, fee)?, scaling_in);
+    let balance_in = Bfp::upscale(token_in.balance, scaling_in);
+    let balance_out = Bfp::upscale(token_out.balance, scaling_out);
+    let weight_in = Bfp::from_big_rational(token_in.weight)?;
+    let weight_out = Bfp::from_big_rational(token_out.weight)?;
+    if amount_in > balance_in.mul_down(&Bfp::from_wei(MAX_IN_RATIO.into())) {
+        return None;
+    }
+
+    let base = balance_in.div_up(&balance_in.add(&amount_in))?;
+    let exponent = weight_in.div_down(&weight_out)?;
+    let power = base.pow_up(&exponent)?;
+    let amount_out = balance_out.mul_down(&power.complement());
+    amount_out
+        .downscale_down(scaling_out)
+        .filter(|amount_out| !amount_out.is_zero())
+}
+
+/// Returns the amount that needs to be sold into the pool in order to receive `amount_out`.
+pub fn get_amount_in(
+    token_in: &WeightedToken,
+    token_out: &WeightedToken,
+    amount_out: U256,
+    fee: &BigRational,
+) -> Option<U256> {
+    if amount_out.is_zero() {
+        return None;
+    }
+    let (scaling_in, scaling_out) = (
+        scaling_factor(token_in.decimals)?,
+        scaling_factor(token_out.decimals)?,
+    );
+    let amount_out = Bfp::upscale(amount_out, scaling_out);
+    let balance_in = Bfp::upscale(token_in.balance, scaling_in);
+    let balance_out = Bfp::upscale(token_out.balance, scaling_out);
+    let weight_in = Bfp::from_big_rational(token_in.weight)?;
+    let weight_out = Bfp::from_big_rational(token_out.weight)?;
+    if amount_out > balance_out.mul_down(&Bfp::from_wei(MAX_OUT_RATIO.into())) {
+        return None;
+    }
+
+    let base = balance_out.div_up(&balance_out.sub(&amount_out)?)?;
+    let exponent = weight_out.div_up(&weight_in)?;
+    let power = base.pow_up(&exponent)?;
+    let amount_in = balance_in.mul_up(&power.sub(&Bfp::one())?);
+    add_swap_fee_amount(amount_in.downscale_up(scaling_in)?, fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weight(percent: u64) -> BigRational {
+        BigRational::new(percent.into(), 100.into())
+    }
+
+    fn fee() -> BigRational {
+        BigRational::new(3.into(), 1000.into())
+    }
+
+    #[test]
+    fn equal_weights_behave_like_constant_product() {
+        let half = weight(50);
+        let token_in = WeightedToken {
+            balance: U256::exp10(24),
+            weight: &half,
+            decimals: 18,
+        };
+        let token_out = WeightedToken {
+            balance: U256::exp10(12),
+            weight: &half,
+            decimals: 6,
+        };
+        // 1e24 * 1e21 * 0.997 / (1e24 + 0.997e21) ~ 996.00...e18 in 18 decimals
+        let amount_out = get_amount_out(&token_in, &token_out, U256::exp10(21), &fee()).unwrap();
+        assert_eq!(amount_out, 996_006_981.into());
+    }
+
+    #[test]
+    fn amount_in_buys_at_least_amount_out() {
+        let (heavy, light) = (weight(80), weight(20));
+        let token_in = WeightedToken {
+            balance: U256::from(123_456_789_123_456_789_123u128),
+            weight: &heavy,
+            decimals: 18,
+        };
+        let token_out = WeightedToken {
+            balance: U256::from(987_654_321_987u64),
+            weight: &light,
+            decimals: 8,
+        };
+        for amount_out in [1u64, 1_000, 1_000_000, 100_000_000_000] {
+            let amount_in =
+                get_amount_in(&token_in, &token_out, amount_out.into(), &fee()).unwrap();
+            let received = get_amount_out(&token_in, &token_out, amount_in, &fee()).unwrap();
+            assert!(received >= amount_out.into());
+        }
+    }
+
+    #[test]
+    fn rejects_trades_above_max_ratios() {
+        let half = weight(50);
+        let token = WeightedToken {
+            balance: 1_000_000.into(),
+            weight: &half,
+            decimals: 18,
+        };
+        assert!(get_amount_out(&token, &token, 400_000.into(), &fee()).is_none());
+        assert!(get_amount_in(&token, &token, 400_000.into(), &fee()).is_none());
+    }
+}
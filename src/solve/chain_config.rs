@@ -0,0 +1,98 @@
+use crate::solve::paraswap_solver::api::DefaultParaswapApi;
+use crate::solve::zeroex_solver::api::DefaultZeroExApi;
+use anyhow::{bail, Result};
+use primitive_types::H160;
+use reqwest::Url;
+
+pub const MAINNET_CHAIN_ID: u64 = 1;
+pub const GOERLI_CHAIN_ID: u64 = 5;
+pub const GNOSIS_CHAIN_ID: u64 = 100;
+
+/// Address of the GPv2 settlement contract, which is the same on all supported chains.
+const SETTLEMENT_CONTRACT: &str = "9008d19f58aabd9ed0d60971565aa8510560ab41";
+
+/// Chain specific addresses and the external liquidity backends available on a chain.
+#[derive(Clone, Debug)]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    /// Wrapped version of the native token. Aggregators report the native token
+    /// with the 0xeee...ee placeholder, which we replace by this token.
+    pub wrapped_native_token: H160,
+    pub settlement_contract: H160,
+    /// Base url of the 0x API, if 0x is available on this chain.
+    pub zeroex_url: Option<Url>,
+    /// Base url of the Paraswap API, if Paraswap is available on this chain.
+    pub paraswap_url: Option<Url>,
+}
+
+impl ChainConfig {
+    pub fn for_chain_id(chain_id: u64) -> Result<Self> {
+        let (wrapped_native_token, zeroex_url, paraswap_url) = match chain_id {
+            MAINNET_CHAIN_ID => (
+                "c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+                Some(DefaultZeroExApi::DEFAULT_URL),
+                Some(DefaultParaswapApi::DEFAULT_URL),
+            ),
+            GOERLI_CHAIN_ID => (
+                "b4fbf271143f4fbf7b91a5ded31805e42b2208d6",
+                Some("https://goerli.api.0x.org/"),
+                None,
+            ),
+            GNOSIS_CHAIN_ID => ("e91d153e0b41518a2ce8dd3d7944fa863463a97d", None, None),
+            _ => bail!("chain id {} is not supported", chain_id),
+        };
+        Ok(Self {
+            chain_id,
+            wrapped_native_token: wrapped_native_token.parse()?,
+            settlement_contract: SETTLEMENT_CONTRACT.parse()?,
+            zeroex_url: zeroex_url.map(Url::parse).transpose()?,
+            paraswap_url: paraswap_url.map(Url::parse).transpose()?,
+        })
+    }
+
+    pub fn supports_zeroex(&self) -> bool {
+        self.zeroex_url.is_some()
+    }
+
+    /// Replaces the native token placeholder used by the aggregators with the wrapped native token.
+    pub fn over_write_native_token(&self, token: H160) -> H160 {
+        if token == H160([0xee; 20]) {
+            self.wrapped_native_token
+        } else {
+            token
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_backends_per_chain() {
+        let mainnet = ChainConfig::for_chain_id(MAINNET_CHAIN_ID).unwrap();
+        assert!(mainnet.supports_zeroex());
+        assert!(mainnet.paraswap_url.is_some());
+
+        let gnosis_chain = ChainConfig::for_chain_id(GNOSIS_CHAIN_ID).unwrap();
+        assert!(!gnosis_chain.supports_zeroex());
+        assert!(gnosis_chain.paraswap_url.is_none());
+
+        assert!(ChainConfig::for_chain_id(1337).is_err());
+    }
+
+    #[test]
+    fn native_token_is_replaced_by_wrapped_token() {
+        let gnosis_chain = ChainConfig::for_chain_id(GNOSIS_CHAIN_ID).unwrap();
+        let wxdai: H160 = "e91d153e0b41518a2ce8dd3d7944fa863463a97d".parse().unwrap();
+        let other_token = H160::from_low_u64_be(1);
+        assert_eq!(
+            gnosis_chain.over_write_native_token(H160([0xee; 20])),
+            wxdai
+        );
+        assert_eq!(
+            gnosis_chain.over_write_native_token(other_token),
+            other_token
+        );
+    }
+}
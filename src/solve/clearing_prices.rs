@@ -0,0 +1,345 @@
+//! Computation of uniform clearing prices from the exchange rates of the trades of a settlement.
+//!
+//! Every trade is an edge between two tokens, whose exchange rate relates their prices. Prices
+//! are propagated along a spanning tree of each connected component of the traded tokens,
+//! starting at the reference token of the settlement, or at the token with the highest normalize
+//! priority in components without it. All other edges close a cycle and have to be consistent
+//! with the propagated prices. Trades on the same directed pair, e.g. of several orders each
+//! settled by their own swap, are combined into one edge at their average rate beforehand, so
+//! callers have to check the limit prices of the orders at the resulting prices.
+
+use crate::models::batch_auction_model::TokenInfoModel;
+use crate::utils::conversions::{big_int_to_u256, U256Ext};
+use anyhow::{anyhow, Result};
+use num::{BigRational, Signed};
+use primitive_types::{H160, U256};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// The reference token, and the root token of every other connected component, has a price of
+/// one with these decimals.
+const ROOT_PRICE_DECIMALS: usize = 18;
+/// Maximal relative deviation of an edge closing a cycle, which accounts for the rounding of
+/// the traded amounts.
+const MAX_RELATIVE_CYCLE_DEVIATION: u64 = 1_000_000_000;
+
+/// A trade of `sell_amount` of the sell token for `buy_amount` of the buy token, which requires
+/// `price(sell_token) * sell_amount == price(buy_token) * buy_amount`.
+#[derive(Clone, Debug)]
+pub struct TradeEdge {
+    pub sell_token: H160,
+    pub buy_token: H160,
+    pub sell_amount: U256,
+    pub buy_amount: U256,
+}
+
+/// The clearing prices of a settlement, relative to the price of its reference token.
+#[derive(Clone, Debug, Default)]
+pub struct ClearingPrices {
+    pub ref_token: Option<H160>,
+    pub prices: HashMap<H160, U256>,
+}
+
+/// Computes the prices of all tokens traded by the edges at once. The reference token is the
+/// traded token with the highest normalize priority, or the wrapped native token if no traded
+/// token has a priority.
+pub fn compute_clearing_prices(
+    edges: &[TradeEdge],
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    wrapped_native_token: H160,
+) -> Result<ClearingPrices> {
+    let edges = &combine_parallel_edges(edges)?;
+    let mut adjacent_edges: BTreeMap<H160, Vec<usize>> = BTreeMap::new();
+    for (index, edge) in edges.iter().enumerate() {
+        adjacent_edges
+            .entry(edge.sell_token)
+            .or_default()
+            .push(index);
+        adjacent_edges
+            .entry(edge.buy_token)
+            .or_default()
+            .push(index);
+    }
+
+    let ref_token = adjacent_edges
+        .keys()
+        .filter(|token| {
+            tokens
+                .get(token)
+                .and_then(|info| info.normalize_priority)
+                .is_some()
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    let ref_token = match ref_token.is_empty() {
+        true => adjacent_edges
+            .contains_key(&wrapped_native_token)
+            .then_some(wrapped_native_token),
+        false => Some(root_token(&ref_token, tokens)),
+    };
+
+    let mut prices: BTreeMap<H160, BigRational> = BTreeMap::new();
+    let mut is_tree_edge = vec![false; edges.len()];
+    for token in adjacent_edges.keys() {
+        if prices.contains_key(token) {
+            continue;
+        }
+        let component = component(*token, edges, &adjacent_edges);
+        let root = match ref_token.filter(|ref_token| component.contains(ref_token)) {
+            Some(ref_token) => ref_token,
+            None => root_token(&component, tokens),
+        };
+        prices.insert(root, BigRational::from_integer(1.into()));
+        let mut queue = VecDeque::from([root]);
+        while let Some(token) = queue.pop_front() {
+            for &index in &adjacent_edges[&token] {
+                let (neighbour, neighbour_price) = {
+                    let edge = &edges[index];
+                    let price = &prices[&token];
+                    if edge.sell_token == token {
+                        let value = price * edge.sell_amount.to_big_rational();
+                        (edge.buy_token, value / edge.buy_amount.to_big_rational())
+                    } else {
+                        let value = price * edge.buy_amount.to_big_rational();
+                        (edge.sell_token, value / edge.sell_amount.to_big_rational())
+                    }
+                };
+                if prices.contains_key(&neighbour) {
+                    continue;
+                }
+                is_tree_edge[index] = true;
+                prices.insert(neighbour, neighbour_price);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+
+    let max_deviation = BigRational::new(1.into(), MAX_RELATIVE_CYCLE_DEVIATION.into());
+    for (edge, _) in edges
+        .iter()
+        .zip(is_tree_edge)
+        .filter(|(_, is_tree_edge)| !is_tree_edge)
+    {
+        let sell_value = &prices[&edge.sell_token] * edge.sell_amount.to_big_rational();
+        let buy_value = &prices[&edge.buy_token] * edge.buy_amount.to_big_rational();
+        if (&sell_value - &buy_value).abs() > sell_value.max(buy_value) * &max_deviation {
+            return Err(anyhow!(
+                "trade of {:?} for {:?} is inconsistent with the prices of the other trades",
+                edge.sell_token,
+                edge.buy_token
+            ));
+        }
+    }
+
+    let root_price = U256::exp10(ROOT_PRICE_DECIMALS).to_big_rational();
+    let prices = prices
+        .into_iter()
+        .map(|(token, price)| {
+            let price = big_int_to_u256(&(price * &root_price).floor().to_integer())
+                .map_err(|_| anyhow!("price of {:?} overflows", token))?;
+            if price.is_zero() {
+                return Err(anyhow!("price of {:?} rounds to zero", token));
+            }
+            Ok((token, price))
+        })
+        .collect::<Result<_>>()?;
+    Ok(ClearingPrices { ref_token, prices })
+}
+
+/// Sums up the amounts of all edges with the same sell and buy token. Edges with a zero amount
+/// have no exchange rate and are rejected.
+fn combine_parallel_edges(edges: &[TradeEdge]) -> Result<Vec<TradeEdge>> {
+    let mut amounts: BTreeMap<(H160, H160), (U256, U256)> = BTreeMap::new();
+    for edge in edges {
+        if edge.sell_amount.is_zero() || edge.buy_amount.is_zero() {
+            return Err(anyhow!(
+                "trade of {:?} for {:?} has a zero amount",
+                edge.sell_token,
+                edge.buy_token
+            ));
+        }
+        let (sell_amount, buy_amount) = amounts
+            .entry((edge.sell_token, edge.buy_token))
+            .or_default();
+        *sell_amount = sell_amount
+            .checked_add(edge.sell_amount)
+            .ok_or_else(|| anyhow!("sell amount of {:?} overflows", edge.sell_token))?;
+        *buy_amount = buy_amount
+            .checked_add(edge.buy_amount)
+            .ok_or_else(|| anyhow!("buy amount of {:?} overflows", edge.buy_token))?;
+    }
+    Ok(amounts
+        .into_iter()
+        .map(
+            |((sell_token, buy_token), (sell_amount, buy_amount))| TradeEdge {
+                sell_token,
+                buy_token,
+                sell_amount,
+                buy_amount,
+            },
+        )
+        .collect())
+}
+
+/// The tokens connected to `token` by the edges.
+fn component(
+    token: H160,
+    edges: &[TradeEdge],
+    adjacent_edges: &BTreeMap<H160, Vec<usize>>,
+) -> Vec<H160> {
+    let mut component = vec![token];
+    let mut index = 0;
+    while let Some(&token) = component.get(index) {
+        for &edge in &adjacent_edges[&token] {
+            for neighbour in [edges[edge].sell_token, edges[edge].buy_token] {
+                if !component.contains(&neighbour) {
+                    component.push(neighbour);
+                }
+            }
+        }
+        index += 1;
+    }
+    component
+}
+
+/// The token with the highest normalize priority, and the smallest address among those.
+fn root_token(component: &[H160], tokens: &BTreeMap<H160, TokenInfoModel>) -> H160 {
+    let priority = |token: &H160| {
+        tokens
+            .get(token)
+            .and_then(|info| info.normalize_priority)
+            .unwrap_or_default()
+    };
+    *component
+        .iter()
+        .max_by(|a, b| priority(a).cmp(&priority(b)).then(b.cmp(a)))
+        .expect("components are never empty")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::btreemap;
+
+    fn edge(sell_token: H160, buy_token: H160, sell_amount: u64, buy_amount: u64) -> TradeEdge {
+        TradeEdge {
+            sell_token,
+            buy_token,
+            sell_amount: sell_amount.into(),
+            buy_amount: buy_amount.into(),
+        }
+    }
+
+    #[test]
+    fn prices_are_relative_to_the_token_with_the_highest_priority() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let tokens = btreemap! {
+            token_b => TokenInfoModel {
+                normalize_priority: Some(1),
+                ..Default::default()
+            },
+        };
+        let edges = vec![edge(token_a, token_b, 1, 2), edge(token_c, token_b, 4, 1)];
+        let prices = compute_clearing_prices(&edges, &tokens, H160::zero()).unwrap();
+
+        let one = U256::exp10(ROOT_PRICE_DECIMALS);
+        assert_eq!(prices.ref_token, Some(token_b));
+        let prices = prices.prices;
+        assert_eq!(prices[&token_b], one);
+        assert_eq!(prices[&token_a], one * 2);
+        assert_eq!(prices[&token_c], one / 4);
+    }
+
+    #[test]
+    fn wrapped_native_token_is_the_fallback_reference_token() {
+        let token_a = H160::from_low_u64_be(1);
+        let weth = H160::from_low_u64_be(2);
+        let edges = vec![edge(token_a, weth, 1, 2)];
+        let prices = compute_clearing_prices(&edges, &BTreeMap::new(), weth).unwrap();
+        assert_eq!(prices.ref_token, Some(weth));
+        assert_eq!(prices.prices[&weth], U256::exp10(ROOT_PRICE_DECIMALS));
+
+        let prices =
+            compute_clearing_prices(&edges, &BTreeMap::new(), H160::from_low_u64_be(3)).unwrap();
+        assert_eq!(prices.ref_token, None);
+        assert_eq!(prices.prices[&token_a], U256::exp10(ROOT_PRICE_DECIMALS));
+    }
+
+    #[test]
+    fn prices_rounding_to_zero_are_rejected() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let edges = vec![TradeEdge {
+            sell_token: token_a,
+            buy_token: token_b,
+            sell_amount: 1.into(),
+            buy_amount: U256::exp10(30),
+        }];
+        let err = compute_clearing_prices(&edges, &BTreeMap::new(), token_a).unwrap_err();
+        assert!(err.to_string().contains("rounds to zero"));
+    }
+
+    #[test]
+    fn consistent_cycles_are_accepted() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let edges = vec![
+            edge(token_a, token_b, 1, 2),
+            edge(token_b, token_c, 1, 3),
+            edge(token_c, token_a, 6, 1),
+        ];
+        let prices = compute_clearing_prices(&edges, &BTreeMap::new(), H160::zero())
+            .unwrap()
+            .prices;
+        assert_eq!(prices.len(), 3);
+        assert_eq!(prices[&token_a], prices[&token_b] * 2);
+    }
+
+    #[test]
+    fn inconsistent_edges_are_reported() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let edges = vec![
+            edge(token_a, token_b, 1, 2),
+            edge(token_b, token_c, 1, 3),
+            edge(token_c, token_a, 5, 1),
+        ];
+        let err = compute_clearing_prices(&edges, &BTreeMap::new(), H160::zero()).unwrap_err();
+        assert!(err.to_string().contains(&format!("{:?}", token_c)));
+    }
+
+    #[test]
+    fn parallel_edges_are_combined() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let edges = vec![
+            edge(token_a, token_b, 1_000, 2_000),
+            edge(token_a, token_b, 1_000, 2_010),
+        ];
+        let prices = compute_clearing_prices(&edges, &BTreeMap::new(), token_b)
+            .unwrap()
+            .prices;
+        let one = U256::exp10(ROOT_PRICE_DECIMALS);
+        assert_eq!(prices[&token_b], one);
+        assert_eq!(prices[&token_a], one * 4_010 / 2_000);
+    }
+
+    #[test]
+    fn disconnected_tokens_get_their_own_root() {
+        let tokens: Vec<H160> = (1..=4).map(H160::from_low_u64_be).collect();
+        let edges = vec![
+            edge(tokens[0], tokens[1], 1, 2),
+            edge(tokens[2], tokens[3], 3, 1),
+        ];
+        let prices = compute_clearing_prices(&edges, &BTreeMap::new(), H160::zero())
+            .unwrap()
+            .prices;
+        let one = U256::exp10(ROOT_PRICE_DECIMALS);
+        assert_eq!(prices[&tokens[0]], one);
+        assert_eq!(prices[&tokens[2]], one);
+        assert_eq!(prices[&tokens[3]], one * 3);
+    }
+}
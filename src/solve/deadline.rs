@@ -0,0 +1,118 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+
+/// Time limit used if the driver did not send one with the instance.
+const DEFAULT_TIME_LIMIT: Duration = Duration::from_secs(30);
+/// Time kept in reserve for building the settlement and sending it back to the driver.
+const RESPONSE_BUFFER: Duration = Duration::from_secs(2);
+/// Percentage of the remaining time the matching of the order book may use for quoting the
+/// residuals of its cows. The rest is reserved for the discovery of trades on Paraswap.
+const ORDER_BOOK_TIME_PERCENTAGE: u32 = 25;
+/// Percentage of the remaining time the discovery of trades may use. The rest is reserved for
+/// the later stages, like trading the left-over amounts on 0x.
+const DISCOVERY_TIME_PERCENTAGE: u32 = 60;
+
+/// Computes the point in time by which all external calls of the solver must be finished.
+pub fn deadline_from_time_limit(time_limit: Option<u64>) -> Instant {
+    let time_limit = time_limit
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIME_LIMIT);
+    // For very short time limits we still want to use at least half of the given time.
+    let usable_time = time_limit
+        .saturating_sub(RESPONSE_BUFFER)
+        .max(time_limit / 2);
+    Instant::now() + usable_time
+}
+
+/// Computes the deadline of the order book matching, which runs before all other stages.
+pub fn order_book_deadline(deadline: Instant) -> Instant {
+    stage_deadline(deadline, ORDER_BOOK_TIME_PERCENTAGE)
+}
+
+/// Computes the deadline of the discovery stage, which may only use part of the time
+/// remaining until `deadline`, so that the stages after it still get a chance to run.
+pub fn discovery_deadline(deadline: Instant) -> Instant {
+    stage_deadline(deadline, DISCOVERY_TIME_PERCENTAGE)
+}
+
+fn stage_deadline(deadline: Instant, percentage: u32) -> Instant {
+    let now = Instant::now();
+    now + deadline.saturating_duration_since(now) * percentage / 100
+}
+
+/// Drives all futures concurrently and returns the outputs of those that completed
+/// before the deadline. Futures still pending at the deadline are dropped, which
+/// cancels their outstanding requests.
+pub async fn collect_until_deadline<F>(
+    futures: impl IntoIterator<Item = F>,
+    deadline: Instant,
+) -> Vec<F::Output>
+where
+    F: Future,
+{
+    let mut pending: FuturesUnordered<F> = futures.into_iter().collect();
+    let mut results = Vec::with_capacity(pending.len());
+    loop {
+        match timeout_at(deadline, pending.next()).await {
+            Ok(Some(result)) => results.push(result),
+            Ok(None) => break,
+            Err(_) => {
+                tracing::debug!(
+                    "Deadline reached, cancelling {} pending requests",
+                    pending.len()
+                );
+                break;
+            }
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    #[test]
+    fn deadline_keeps_buffer_for_response() {
+        let now = Instant::now();
+        let deadline = deadline_from_time_limit(Some(10));
+        assert!(deadline <= now + Duration::from_secs(9));
+        assert!(deadline >= now + Duration::from_secs(7));
+
+        // Short limits are not consumed completely by the buffer.
+        let deadline = deadline_from_time_limit(Some(2));
+        assert!(deadline > now);
+    }
+
+    #[test]
+    fn stages_leave_time_for_later_stages() {
+        let now = Instant::now();
+        let deadline = now + Duration::from_secs(10);
+        let discovery = discovery_deadline(deadline);
+        assert!(discovery >= now + Duration::from_secs(5));
+        assert!(discovery <= deadline - Duration::from_secs(3));
+
+        let order_book = order_book_deadline(deadline);
+        assert!(order_book >= now + Duration::from_secs(2));
+        assert!(order_book <= now + Duration::from_secs(3));
+
+        // A deadline in the past stays in the past.
+        assert!(discovery_deadline(now) <= Instant::now());
+    }
+
+    #[tokio::test]
+    async fn collect_until_deadline_drops_slow_futures() {
+        async fn delayed(value: u32, millis: u64) -> u32 {
+            sleep(Duration::from_millis(millis)).await;
+            value
+        }
+        let futures = vec![delayed(1, 1), delayed(2, 60_000), delayed(3, 0)];
+        let mut results =
+            collect_until_deadline(futures, Instant::now() + Duration::from_millis(100)).await;
+        results.sort_unstable();
+        assert_eq!(results, vec![1, 3]);
+    }
+}
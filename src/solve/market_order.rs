@@ -0,0 +1,338 @@
+//! Classification of orders by their limit price relative to the external prices.
+//!
+//! Market orders ask for about what their sell amount is worth at the external prices, or less,
+//! and are likely to be matched. The external prices are denominated per atom of a token, so
+//! comparing the values of the atom amounts of an order accounts for the decimals of both of
+//! its tokens. All values are computed exactly, for amounts of any size.
+
+use crate::models::batch_auction_model::{OrderModel, TokenInfoModel};
+use crate::solve::objective::external_price;
+use crate::utils::conversions::U256Ext;
+use num::{BigRational, One, Signed};
+use primitive_types::H160;
+use std::collections::BTreeMap;
+
+/// Relative amount by which the limit price of a market order may be worse than the external
+/// prices.
+pub const DEFAULT_MARKET_ORDER_TOLERANCE: f64 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderClass {
+    Market,
+    Limit,
+    /// The order can not be classified, because an external price is missing.
+    Unknown,
+}
+
+/// Classifies an order with the given relative tolerance. Sell orders are market orders if
+/// their sell amount is worth more than their buy amount reduced by the tolerance, buy orders
+/// if their buy amount is worth less than their sell amount increased by the tolerance.
+///
+/// The external prices are per atom, so the values of the atom amounts are compared directly
+/// and the `decimals` of the tokens are not needed.
+pub fn classify_order(
+    order: &OrderModel,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    tolerance: &BigRational,
+) -> OrderClass {
+    let price = |token| {
+        external_price(tokens, token)
+            .ok()
+            .filter(|p| p.is_positive())
+    };
+    let (sell_price, buy_price) = match (price(order.sell_token), price(order.buy_token)) {
+        (Some(sell_price), Some(buy_price)) => (sell_price, buy_price),
+        _ => return OrderClass::Unknown,
+    };
+    let sell_value = order.sell_amount.to_big_rational() * sell_price;
+    let buy_value = order.buy_amount.to_big_rational() * buy_price;
+    let is_market_order = if order.is_sell_order {
+        sell_value > buy_value * (BigRational::one() - tolerance)
+    } else {
+        buy_value < sell_value * (BigRational::one() + tolerance)
+    };
+    match is_market_order {
+        true => OrderClass::Market,
+        false => OrderClass::Limit,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::batch_auction_model::{CostModel, FeeModel};
+    use core::array::IntoIter;
+    use maplit::btreemap;
+    use primitive_types::U256;
+
+    fn tolerance() -> BigRational {
+        BigRational::from_float(DEFAULT_MARKET_ORDER_TOLERANCE).unwrap()
+    }
+
+    #[test]
+    fn check_for_market_order_with_different_decimal() {
+        let mim: H160 = "99d8a9c45b2eca8864373a26d1459e3dff1e17f3".parse().unwrap();
+        let usdc: H160 = "a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".parse().unwrap();
+        let mim_usdc_sell_order = OrderModel {
+            sell_token: mim,
+            buy_token: usdc,
+            sell_amount: 85670806275371642755219456u128.into(),
+            buy_amount: 85593297939394u128.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel {
+                amount: U256::from(0u32),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+            fee: FeeModel {
+                amount: U256::from(0u32),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+        };
+        let tokens = BTreeMap::from_iter(IntoIter::new([
+            (
+                mim,
+                TokenInfoModel {
+                    decimals: Some(18u8),
+                    external_price: Some(0.00040788388716066107f64),
+                    ..Default::default()
+                },
+            ),
+            (
+                usdc,
+                TokenInfoModel {
+                    decimals: Some(6u8),
+                    external_price: Some(405525120.6406718f64),
+                    ..Default::default()
+                },
+            ),
+        ]));
+        assert_eq!(
+            classify_order(&mim_usdc_sell_order, &tokens, &tolerance()),
+            OrderClass::Market
+        );
+    }
+
+    #[test]
+    fn check_for_market_order() {
+        let dai: H160 = "4e3fbd56cd56c3e72c1403e103b45db9da5b9d2b".parse().unwrap();
+        let usdc: H160 = "d533a949740bb3306d119cc777fa900ba034cd52".parse().unwrap();
+
+        let dai_usdc_sell_order = OrderModel {
+            sell_token: dai,
+            buy_token: usdc,
+            sell_amount: 1_001_000_000_000_000_000u128.into(),
+            buy_amount: 1_000_000u128.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+            fee: FeeModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+        };
+        let dai_usdc_buy_order = OrderModel {
+            sell_token: dai,
+            buy_token: usdc,
+            sell_amount: 1_001_000_000_000_000_000u128.into(),
+            buy_amount: 1_000_000u128.into(),
+            is_sell_order: false,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+            fee: FeeModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+        };
+        let tokens = BTreeMap::from_iter(IntoIter::new([
+            (
+                dai,
+                TokenInfoModel {
+                    decimals: Some(18u8),
+                    external_price: Some(1.00f64),
+                    ..Default::default()
+                },
+            ),
+            (
+                usdc,
+                TokenInfoModel {
+                    decimals: Some(6u8),
+                    external_price: Some(1000000000000.0f64),
+                    ..Default::default()
+                },
+            ),
+        ]));
+        assert_eq!(
+            classify_order(&dai_usdc_sell_order, &tokens, &tolerance()),
+            OrderClass::Market
+        );
+        assert_eq!(
+            classify_order(&dai_usdc_buy_order, &tokens, &tolerance()),
+            OrderClass::Market
+        );
+
+        let tokens = BTreeMap::from_iter(IntoIter::new([
+            (
+                dai,
+                TokenInfoModel {
+                    decimals: Some(18u8),
+                    external_price: Some(1.00f64),
+                    ..Default::default()
+                },
+            ),
+            (
+                usdc,
+                TokenInfoModel {
+                    decimals: Some(6u8),
+                    external_price: Some(1020000000000.0f64),
+                    ..Default::default()
+                },
+            ),
+        ]));
+        assert_eq!(
+            classify_order(&dai_usdc_sell_order, &tokens, &tolerance()),
+            OrderClass::Limit
+        );
+        assert_eq!(
+            classify_order(&dai_usdc_buy_order, &tokens, &tolerance()),
+            OrderClass::Limit
+        );
+        let weth: H160 = "4e3fbd56cd56c3e72c1403e103b45db9da5b9d2b".parse().unwrap();
+        let usdc_weth_order = OrderModel {
+            sell_token: usdc,
+            buy_token: weth,
+            sell_amount: 4_002_000_000u128.into(),
+            buy_amount: 1_000_000_000_000_000_000u128.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+            fee: FeeModel {
+                amount: U256::from(0),
+                token: "6b175474e89094c44da98b954eedeac495271d0f".parse().unwrap(),
+            },
+        };
+        let tokens = BTreeMap::from_iter(IntoIter::new([
+            (
+                weth,
+                TokenInfoModel {
+                    decimals: Some(18u8),
+                    external_price: Some(1.00f64),
+                    ..Default::default()
+                },
+            ),
+            (
+                usdc,
+                TokenInfoModel {
+                    decimals: Some(6u8),
+                    external_price: Some(400000000.0f64),
+                    ..Default::default()
+                },
+            ),
+        ]));
+        assert_eq!(
+            classify_order(&usdc_weth_order, &tokens, &tolerance()),
+            OrderClass::Market
+        );
+    }
+
+    #[test]
+    fn prices_per_atom_account_for_mixed_decimals() {
+        let usdc = H160::from_low_u64_be(1);
+        let dai = H160::from_low_u64_be(2);
+        // Selling 1000 USDC (6 decimals) for DAI (18 decimals), both worth 1 DAI.
+        let order = |buy_amount: U256| OrderModel {
+            sell_token: usdc,
+            buy_token: dai,
+            sell_amount: U256::exp10(9),
+            buy_amount,
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: usdc,
+            },
+        };
+        let tokens = |usdc_decimals, dai_decimals| {
+            btreemap! {
+                usdc => TokenInfoModel {
+                    decimals: usdc_decimals,
+                    external_price: Some(1e12),
+                    ..Default::default()
+                },
+                dai => TokenInfoModel {
+                    decimals: dai_decimals,
+                    external_price: Some(1.0),
+                    ..Default::default()
+                },
+            }
+        };
+        let market_order = order(U256::exp10(18) * 1_000);
+        let limit_order = order(U256::exp10(18) * 1_010);
+        // The decimals do not change the classification, they are included in the prices.
+        for tokens in [tokens(Some(6), Some(18)), tokens(None, None)] {
+            assert_eq!(
+                classify_order(&market_order, &tokens, &tolerance()),
+                OrderClass::Market
+            );
+            assert_eq!(
+                classify_order(&limit_order, &tokens, &tolerance()),
+                OrderClass::Limit
+            );
+        }
+    }
+
+    #[test]
+    fn orders_above_u128_are_classified() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: U256::exp10(40),
+            buy_amount: U256::exp10(40) * 2,
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let tokens = btreemap! {
+            sell_token => TokenInfoModel {
+                external_price: Some(2.0),
+                ..Default::default()
+            },
+            buy_token => TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+        };
+        assert_eq!(
+            classify_order(&order, &tokens, &tolerance()),
+            OrderClass::Market
+        );
+        let zero = BigRational::from_integer(0.into());
+        assert_eq!(classify_order(&order, &tokens, &zero), OrderClass::Limit);
+        assert_eq!(
+            classify_order(&order, &BTreeMap::new(), &tolerance()),
+            OrderClass::Unknown
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Evaluation of the objective of a solution, in order to choose between candidate solutions.
+//!
+//! The objective is the surplus of the executed user orders minus the costs of all executed
+//! orders, the costs of the used amms and the gas costs of the interactions. Liquidity orders
+//! don't contribute any surplus. All values are denominated in the native token, using the
+//! external prices of the instance.
+
+use crate::models::batch_auction_model::{
+    AmmModel, CostModel, InteractionData, OrderModel, SettledBatchAuctionModel, TokenInfoModel,
+};
+use crate::utils::conversions::{big_rational_to_float, U256Ext};
+use anyhow::{anyhow, Result};
+use num::{BigRational, Zero};
+use primitive_types::H160;
+use std::collections::BTreeMap;
+
+/// Function selector of the ERC20 `approve` method.
+const APPROVE_SELECTOR: [u8; 4] = [0x09, 0x5e, 0xa7, 0xb3];
+/// Gas estimate of an ERC20 approval.
+const APPROVAL_GAS: u64 = 46_000;
+/// Gas estimate of any other interaction, e.g. a zeroEx swap.
+const SWAP_INTERACTION_GAS: u64 = 200_000;
+
+/// Computes the objective value of a solution for the given orders. The executed amounts of
+/// the orders are read from the solution, their limit prices from the orders.
+pub fn evaluate(
+    solution: &SettledBatchAuctionModel,
+    orders: &[(usize, OrderModel)],
+    amms: &BTreeMap<usize, AmmModel>,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    gas_price: Option<f64>,
+) -> Result<f64> {
+    let mut objective = BigRational::zero();
+    for (index, order) in orders {
+        if solution.orders.contains_key(index) {
+            if !order.is_liquidity_order {
+                objective += order_surplus(solution, *index, order, tokens)?;
+            }
+            objective -= cost_value(&order.cost, tokens)?;
+        }
+    }
+    for (index, updated_amm) in &solution.amms {
+        if !updated_amm.is_non_trivial() {
+            continue;
+        }
+        let amm = amms
+            .get(index)
+            .ok_or_else(|| anyhow!("amm {} is not part of the instance", index))?;
+        objective -= cost_value(&amm.cost, tokens)?;
+    }
+    match gas_price {
+        Some(gas_price) => {
+            let gas_price = BigRational::from_float(gas_price)
+                .ok_or_else(|| anyhow!("invalid gas price {}", gas_price))?;
+            objective -=
+                BigRational::from_integer(interactions_gas(&solution.interaction_data).into())
+                    * gas_price;
+        }
+        None => tracing::debug!("No gas price available, ignoring the gas costs of interactions"),
+    }
+    big_rational_to_float(&objective).ok_or_else(|| anyhow!("objective is not representable"))
+}
+
+/// Estimates the surplus of fully executing an order at the external prices, in the native
+/// token. This is the value of what the order sells minus the value of what it asks for.
+pub fn estimated_order_surplus(
+    order: &OrderModel,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+) -> Option<BigRational> {
+    let sell_value =
+        order.sell_amount.to_big_rational() * external_price(tokens, order.sell_token).ok()?;
+    let buy_value =
+        order.buy_amount.to_big_rational() * external_price(tokens, order.buy_token).ok()?;
+    Some(sell_value - buy_value)
+}
+
+/// The surplus of an executed order in the native token. Sell orders get their surplus in the
+/// buy token, buy orders in the sell token.
+fn order_surplus(
+    solution: &SettledBatchAuctionModel,
+    index: usize,
+    order: &OrderModel,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+) -> Result<BigRational> {
+    let executed_order = &solution.orders[&index];
+    let sell_price = clearing_price(solution, order.sell_token)?;
+    let buy_price = clearing_price(solution, order.buy_token)?;
+    if order.sell_amount.is_zero() || order.buy_amount.is_zero() || buy_price.is_zero() {
+        return Err(anyhow!("order {} can not be evaluated", index));
+    }
+    let limit_price = order.buy_amount.to_big_rational() / order.sell_amount.to_big_rational();
+    if order.is_sell_order {
+        let executed_sell_amount = executed_order.exec_sell_amount.to_big_rational();
+        let received = &executed_sell_amount * sell_price / buy_price;
+        let limit_amount = executed_sell_amount * limit_price;
+        Ok((received - limit_amount) * external_price(tokens, order.buy_token)?)
+    } else {
+        let executed_buy_amount = executed_order.exec_buy_amount.to_big_rational();
+        let paid = &executed_buy_amount * buy_price / sell_price;
+        let limit_amount = executed_buy_amount / limit_price;
+        Ok((limit_amount - paid) * external_price(tokens, order.sell_token)?)
+    }
+}
+
+fn clearing_price(solution: &SettledBatchAuctionModel, token: H160) -> Result<BigRational> {
+    solution
+        .prices
+        .get(&token)
+        .map(|price| price.to_big_rational())
+        .filter(|price| !price.is_zero())
+        .ok_or_else(|| anyhow!("no clearing price for token {:?}", token))
+}
+
+/// The external price of a token, i.e. the value of one unit of it in the native token.
+pub fn external_price(tokens: &BTreeMap<H160, TokenInfoModel>, token: H160) -> Result<BigRational> {
+    tokens
+        .get(&token)
+        .and_then(|info| info.external_price)
+        .and_then(BigRational::from_float)
+        .ok_or_else(|| anyhow!("no external price for token {:?}", token))
+}
+
+fn cost_value(cost: &CostModel, tokens: &BTreeMap<H160, TokenInfoModel>) -> Result<BigRational> {
+    if cost.amount.is_zero() {
+        return Ok(BigRational::zero());
+    }
+    Ok(cost.amount.to_big_rational() * external_price(tokens, cost.token)?)
+}
+
+/// Estimates the gas used by the interactions of a solution.
+pub fn interactions_gas(interactions: &[InteractionData]) -> u64 {
+    interactions
+        .iter()
+        .map(|interaction| {
+            if interaction.call_data.0.starts_with(&APPROVE_SELECTOR) {
+                APPROVAL_GAS
+            } else {
+                SWAP_INTERACTION_GAS
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::batch_auction_model::{ExecutedOrderModel, FeeModel};
+    use maplit::{btreemap, hashmap};
+    use primitive_types::U256;
+
+    fn token_info(external_price: f64) -> TokenInfoModel {
+        TokenInfoModel {
+            external_price: Some(external_price),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn objective_is_surplus_minus_costs() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 900.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel {
+                amount: 10.into(),
+                token: buy_token,
+            },
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let solution = SettledBatchAuctionModel {
+            orders: hashmap! {
+                0 => ExecutedOrderModel {
+                    exec_sell_amount: 1_000.into(),
+                    exec_buy_amount: 950.into(),
+                },
+            },
+            // the order receives 950 of the buy token
+            prices: hashmap! {
+                sell_token => 950.into(),
+                buy_token => 1_000.into(),
+            },
+            interaction_data: vec![InteractionData {
+                target: H160::zero(),
+                value: U256::zero(),
+                call_data: ethcontract::Bytes(APPROVE_SELECTOR.to_vec()),
+            }],
+            ..Default::default()
+        };
+        let tokens = btreemap! {
+            sell_token => token_info(1.0),
+            buy_token => token_info(2.0),
+        };
+
+        // surplus of 50 buy tokens, minus 10 buy tokens of costs, valued at 2 each
+        let objective = evaluate(
+            &solution,
+            &[(0, order.clone())],
+            &BTreeMap::new(),
+            &tokens,
+            None,
+        );
+        assert_eq!(objective.unwrap(), 80.0);
+        // minus the gas of one approval
+        let objective = evaluate(
+            &solution,
+            &[(0, order)],
+            &BTreeMap::new(),
+            &tokens,
+            Some(0.001),
+        );
+        assert_eq!(objective.unwrap(), 80.0 - 46.0);
+    }
+
+    #[test]
+    fn liquidity_orders_only_contribute_their_costs() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let liquidity_order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 500.into(),
+            is_sell_order: true,
+            is_liquidity_order: true,
+            allow_partial_fill: true,
+            cost: CostModel {
+                amount: 10.into(),
+                token: sell_token,
+            },
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let solution = SettledBatchAuctionModel {
+            orders: hashmap! {
+                0 => ExecutedOrderModel {
+                    exec_sell_amount: 1_000.into(),
+                    exec_buy_amount: 1_000.into(),
+                },
+            },
+            prices: hashmap! {
+                sell_token => 1.into(),
+                buy_token => 1.into(),
+            },
+            ..Default::default()
+        };
+        let tokens = btreemap! {
+            sell_token => token_info(1.0),
+            buy_token => token_info(1.0),
+        };
+        let objective = evaluate(
+            &solution,
+            &[(0, liquidity_order)],
+            &BTreeMap::new(),
+            &tokens,
+            None,
+        );
+        assert_eq!(objective.unwrap(), -10.0);
+    }
+
+    #[test]
+    fn estimated_surplus_uses_external_prices() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 400.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let tokens = btreemap! {
+            sell_token => token_info(1.0),
+            buy_token => token_info(2.0),
+        };
+        assert_eq!(
+            estimated_order_surplus(&order, &tokens),
+            Some(BigRational::from_integer(200.into()))
+        );
+        assert_eq!(estimated_order_surplus(&order, &BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn missing_prices_can_not_be_evaluated() {
+        let sell_token = H160::from_low_u64_be(1);
+        let buy_token = H160::from_low_u64_be(2);
+        let order = OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: 900.into(),
+            is_sell_order: false,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        };
+        let solution = SettledBatchAuctionModel {
+            orders: hashmap! {
+                0 => ExecutedOrderModel {
+                    exec_sell_amount: 1_000.into(),
+                    exec_buy_amount: 900.into(),
+                },
+            },
+            prices: hashmap! {
+                sell_token => 900.into(),
+                buy_token => 1_000.into(),
+            },
+            ..Default::default()
+        };
+        let tokens = btreemap! { buy_token => token_info(1.0) };
+        assert!(evaluate(&solution, &[(0, order)], &BTreeMap::new(), &tokens, None).is_err());
+    }
+}
@@ -0,0 +1,765 @@
+//! Matching of opposite orders on the same token pair directly against each other.
+//!
+//! The orders of a pair are cleared at a uniform price, which is taken from the external
+//! prices of the instance. The overlapping volume of both sides is exchanged within the
+//! settlement without any interactions. Only the residual volume of the heavier side is traded
+//! against external liquidity, in which case the pair is cleared at the rate of that trade.
+//! As the residual volume itself depends on the clearing rate, both are recomputed until they
+//! agree.
+//!
+//! Orders which trade in a cycle over three or more tokens, e.g. A→B→C→A, are matched as a
+//! ring. Rings are cleared at the external prices and need no interactions either.
+
+use crate::models::batch_auction_model::{OrderModel, TokenInfoModel};
+use crate::solve::amm_solver::AmmRoute;
+use crate::solve::objective::external_price;
+use crate::solve::partially_filled_order;
+use crate::solve::zeroex_solver::api::SwapResponse;
+use crate::utils::conversions::{big_int_to_u256, U256Ext};
+use num::{BigInt, BigRational, Integer, One, Signed, Zero};
+use primitive_types::{H160, U256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+
+/// Maximal number of tokens of a ring.
+const MAX_RING_LENGTH: usize = 4;
+/// Decimals of the clearing prices of rings, relative to the external prices.
+const RING_PRICE_DECIMALS: usize = 18;
+/// Maximal number of times the residual of a pair is quoted until it agrees with the clearing
+/// rate.
+const MAX_CLEARING_ITERATIONS: usize = 5;
+
+/// The trade of the residual volume of a pair against external liquidity.
+#[derive(Clone, Debug)]
+pub enum Residual {
+    Amm(AmmRoute),
+    ZeroEx(SwapResponse),
+}
+
+impl Residual {
+    pub fn sell_amount(&self) -> U256 {
+        match self {
+            Residual::Amm(route) => route.sell_amount(),
+            Residual::ZeroEx(swap) => swap.sell_amount,
+        }
+    }
+
+    pub fn buy_amount(&self) -> U256 {
+        match self {
+            Residual::Amm(route) => route.buy_amount(),
+            Residual::ZeroEx(swap) => swap.buy_amount,
+        }
+    }
+}
+
+/// The orders of a token pair, which are settled against each other.
+#[derive(Clone, Debug)]
+pub struct CowMatch {
+    /// The token of which the orders sell more than the opposite orders buy.
+    pub sell_token: H160,
+    pub buy_token: H160,
+    /// The amount of the sell token received by the opposite orders.
+    pub cow_sell_amount: U256,
+    /// The amount of the buy token paid by the opposite orders.
+    pub cow_buy_amount: U256,
+    /// The trade of the residual amount of the sell token, if any.
+    pub residual: Option<Residual>,
+    pub orders: Vec<(usize, OrderModel)>,
+}
+
+impl CowMatch {
+    pub fn sell_amount(&self) -> U256 {
+        self.cow_sell_amount
+            + self
+                .residual
+                .as_ref()
+                .map(Residual::sell_amount)
+                .unwrap_or_default()
+    }
+
+    pub fn buy_amount(&self) -> U256 {
+        self.cow_buy_amount
+            + self
+                .residual
+                .as_ref()
+                .map(Residual::buy_amount)
+                .unwrap_or_default()
+    }
+
+    /// The amounts whose ratio is the clearing rate of the pair: the residual trade if there
+    /// is one, as all orders are cleared at its rate, and otherwise the overlapping volume.
+    pub fn clearing_amounts(&self) -> (U256, U256) {
+        match &self.residual {
+            Some(residual) => (residual.sell_amount(), residual.buy_amount()),
+            None => (self.cow_sell_amount, self.cow_buy_amount),
+        }
+    }
+
+    fn clearing_rate(&self) -> Option<BigRational> {
+        let (sell_amount, buy_amount) = self.clearing_amounts();
+        if sell_amount.is_zero() {
+            return None;
+        }
+        Some(buy_amount.to_big_rational() / sell_amount.to_big_rational())
+    }
+}
+
+/// Orders trading in a cycle over three or more tokens.
+#[derive(Clone, Debug)]
+pub struct RingMatch {
+    /// The clearing prices of the tokens of the ring.
+    pub prices: BTreeMap<H160, U256>,
+    /// The orders of the ring in the order of the cycle, reduced to their executed amounts.
+    pub orders: Vec<(usize, OrderModel)>,
+}
+
+/// Groups the orders by their token pair, regardless of their direction.
+pub fn orders_by_pair(
+    orders: &[(usize, OrderModel)],
+) -> BTreeMap<(H160, H160), Vec<(usize, OrderModel)>> {
+    let mut orders_by_pair: BTreeMap<(H160, H160), Vec<(usize, OrderModel)>> = BTreeMap::new();
+    for (index, order) in orders {
+        let pair = if order.sell_token < order.buy_token {
+            (order.sell_token, order.buy_token)
+        } else {
+            (order.buy_token, order.sell_token)
+        };
+        orders_by_pair
+            .entry(pair)
+            .or_default()
+            .push((*index, order.clone()));
+    }
+    orders_by_pair
+}
+
+/// Matches the orders of a token pair with orders in both directions. The residual is quoted
+/// with `quote`, which returns the trade selling the given amount of the first token for the
+/// second one. Pairs whose residual can not be traded within the limit prices of the orders,
+/// or whose settlement would pay out more of a token than it receives, are not matched.
+pub async fn match_pair<F, Fut>(
+    token_a: H160,
+    token_b: H160,
+    orders: &[(usize, OrderModel)],
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    mut quote: F,
+) -> Option<CowMatch>
+where
+    F: FnMut(H160, H160, U256) -> Fut,
+    Fut: Future<Output = Option<Residual>>,
+{
+    // The external price of token a in token b. Tokens without a positive price have no rate.
+    let price = |token| {
+        external_price(tokens, token)
+            .ok()
+            .filter(|price| price.is_positive())
+    };
+    let external_rate = price(token_a)? / price(token_b)?;
+    let orders: Vec<(usize, OrderModel)> = orders
+        .iter()
+        .filter(|(_, order)| satisfies_limit_price(order, token_a, &external_rate))
+        .cloned()
+        .collect();
+    if !orders.iter().any(|(_, order)| order.sell_token == token_a)
+        || !orders.iter().any(|(_, order)| order.sell_token == token_b)
+    {
+        return None;
+    }
+
+    let volumes_a = Volumes::new(&orders, token_a, &external_rate);
+    let (sell_token, buy_token, mut rate) = if volumes_a.sold >= volumes_a.bought {
+        (token_a, token_b, external_rate)
+    } else {
+        (token_b, token_a, external_rate.recip())
+    };
+    // The residual is sold at a different rate than the external one, which changes the
+    // volumes of the orders, and with them the residual.
+    let mut residual: Option<Residual> = None;
+    for _ in 0..MAX_CLEARING_ITERATIONS {
+        let excess = big_int_to_u256(&Volumes::new(&orders, sell_token, &rate).excess()).ok()?;
+        let residual_amount = residual
+            .as_ref()
+            .map(Residual::sell_amount)
+            .unwrap_or_default();
+        if excess == residual_amount {
+            break;
+        }
+        if excess.is_zero() {
+            residual = None;
+            break;
+        }
+        let next_residual = quote(sell_token, buy_token, excess).await?;
+        if next_residual.sell_amount().is_zero() || next_residual.buy_amount().is_zero() {
+            return None;
+        }
+        rate = next_residual.buy_amount().to_big_rational()
+            / next_residual.sell_amount().to_big_rational();
+        residual = Some(next_residual);
+    }
+
+    let volumes = Volumes::new(&orders, sell_token, &rate);
+    let cow_match = CowMatch {
+        sell_token,
+        buy_token,
+        cow_sell_amount: big_int_to_u256(&volumes.bought).ok()?,
+        cow_buy_amount: big_int_to_u256(&volumes.received).ok()?,
+        residual,
+        orders,
+    };
+    if cow_match.cow_sell_amount.is_zero() || cow_match.cow_buy_amount.is_zero() {
+        return None;
+    }
+    let clearing_rate = cow_match.clearing_rate()?;
+    if !cow_match
+        .orders
+        .iter()
+        .all(|(_, order)| satisfies_limit_price(order, sell_token, &clearing_rate))
+    {
+        tracing::debug!(
+            "Orders on pair {:?} can not be matched at the rate of the residual",
+            (token_a, token_b)
+        );
+        return None;
+    }
+    // The settlement must receive at least as much of both tokens as it pays out.
+    let volumes = Volumes::new(&cow_match.orders, sell_token, &clearing_rate);
+    let (residual_sell_amount, residual_buy_amount) = cow_match
+        .residual
+        .as_ref()
+        .map(|residual| (residual.sell_amount(), residual.buy_amount()))
+        .unwrap_or_default();
+    let sell_token_balance = volumes.excess() - residual_sell_amount.to_big_int();
+    let buy_token_balance = &volumes.received + residual_buy_amount.to_big_int() - &volumes.paid;
+    if sell_token_balance.is_negative() || buy_token_balance.is_negative() {
+        tracing::debug!(
+            "Orders on pair {:?} can not be matched, the settlement would be short of {:?}",
+            (token_a, token_b),
+            (sell_token_balance, buy_token_balance)
+        );
+        return None;
+    }
+    tracing::debug!(
+        "Matched {} orders on pair {:?} in the order book",
+        cow_match.orders.len(),
+        (token_a, token_b)
+    );
+    Some(cow_match)
+}
+
+/// Finds rings among the orders and clears them at the external prices. Every token is part
+/// of at most one ring, and a ring is only matched if the settlement receives at least as much
+/// of every token as it pays out.
+pub fn match_rings(
+    orders: &[(usize, OrderModel)],
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+) -> Vec<RingMatch> {
+    let prices: BTreeMap<H160, U256> = orders
+        .iter()
+        .flat_map(|(_, order)| [order.sell_token, order.buy_token])
+        .filter_map(|token| Some((token, ring_price(tokens, token)?)))
+        .collect();
+    let orders: Vec<&(usize, OrderModel)> = orders
+        .iter()
+        .filter(
+            |(_, order)| match (prices.get(&order.sell_token), prices.get(&order.buy_token)) {
+                (Some(sell_price), Some(buy_price)) => {
+                    order.sell_token != order.buy_token
+                        && order.buy_amount.full_mul(*buy_price)
+                            <= order.sell_amount.full_mul(*sell_price)
+                }
+                _ => false,
+            },
+        )
+        .collect();
+
+    let start_tokens: BTreeSet<H160> = orders.iter().map(|(_, order)| order.sell_token).collect();
+    let mut used_tokens = BTreeSet::new();
+    let mut rings = Vec::new();
+    for start in start_tokens {
+        if used_tokens.contains(&start) {
+            continue;
+        }
+        let mut path = Vec::new();
+        if let Some(ring) = find_ring(start, start, &orders, &prices, &used_tokens, &mut path) {
+            tracing::debug!(
+                "Matched a ring of {} orders in the order book",
+                ring.orders.len()
+            );
+            used_tokens.extend(ring.prices.keys().copied());
+            rings.push(ring);
+        }
+    }
+    rings
+}
+
+/// The external price of the token as integer with `RING_PRICE_DECIMALS` decimals.
+fn ring_price(tokens: &BTreeMap<H160, TokenInfoModel>, token: H160) -> Option<U256> {
+    let price = external_price(tokens, token).ok()?
+        * BigRational::from_integer(BigInt::from(10u64).pow(RING_PRICE_DECIMALS as u32));
+    to_u256_floor(&price).filter(|price| !price.is_zero())
+}
+
+/// Extends the path of orders from `start` to `token` into a ring, depth first. The smallest
+/// token of a ring is its start, so that every ring is only visited once.
+fn find_ring<'a>(
+    start: H160,
+    token: H160,
+    orders: &[&'a (usize, OrderModel)],
+    prices: &BTreeMap<H160, U256>,
+    used_tokens: &BTreeSet<H160>,
+    path: &mut Vec<&'a (usize, OrderModel)>,
+) -> Option<RingMatch> {
+    for &order in orders.iter().filter(|(_, order)| order.sell_token == token) {
+        let next_token = order.1.buy_token;
+        let ring = if next_token == start {
+            if path.len() + 1 < 3 {
+                continue;
+            }
+            path.push(order);
+            settle_ring(path, prices)
+        } else {
+            if next_token < start
+                || used_tokens.contains(&next_token)
+                || path.iter().any(|(_, order)| order.sell_token == next_token)
+                || path.len() + 1 >= MAX_RING_LENGTH
+            {
+                continue;
+            }
+            path.push(order);
+            find_ring(start, next_token, orders, prices, used_tokens, path)
+        };
+        path.pop();
+        if ring.is_some() {
+            return ring;
+        }
+    }
+    None
+}
+
+/// Executes every order of the ring for the value of the smallest one, which requires the
+/// larger orders to be partially fillable.
+fn settle_ring(path: &[&(usize, OrderModel)], prices: &BTreeMap<H160, U256>) -> Option<RingMatch> {
+    let value = |order: &OrderModel| match order.is_sell_order {
+        true => order.sell_amount.to_big_int() * prices[&order.sell_token].to_big_int(),
+        false => order.buy_amount.to_big_int() * prices[&order.buy_token].to_big_int(),
+    };
+    let ring_value = path.iter().map(|(_, order)| value(order)).min()?;
+
+    let mut ring_orders = Vec::new();
+    let mut balances: BTreeMap<H160, BigInt> = BTreeMap::new();
+    for (index, order) in path.iter().copied() {
+        let sell_price = prices[&order.sell_token].to_big_int();
+        let buy_price = prices[&order.buy_token].to_big_int();
+        let executed_order = if value(order) == ring_value {
+            order.clone()
+        } else if order.allow_partial_fill {
+            let executed_amount = match order.is_sell_order {
+                true => ring_value.div_floor(&sell_price),
+                false => ring_value.div_floor(&buy_price),
+            };
+            partially_filled_order(order, big_int_to_u256(&executed_amount).ok()?)?
+        } else {
+            return None;
+        };
+        // The amounts the settlement transfers at the clearing prices, which round in favour
+        // of the settlement.
+        let (sold, bought) = match executed_order.is_sell_order {
+            true => {
+                let sold = executed_order.sell_amount.to_big_int();
+                let bought = (&sold * &sell_price).div_floor(&buy_price);
+                if bought < executed_order.buy_amount.to_big_int() {
+                    return None;
+                }
+                (sold, bought)
+            }
+            false => {
+                let bought = executed_order.buy_amount.to_big_int();
+                let sold = (&bought * &buy_price).div_ceil(&sell_price);
+                if sold > executed_order.sell_amount.to_big_int() {
+                    return None;
+                }
+                (sold, bought)
+            }
+        };
+        if bought.is_zero() {
+            return None;
+        }
+        *balances.entry(order.sell_token).or_default() += sold;
+        *balances.entry(order.buy_token).or_default() -= bought;
+        ring_orders.push((*index, executed_order));
+    }
+    if balances.values().any(|balance| balance.is_negative()) {
+        return None;
+    }
+    Some(RingMatch {
+        prices: balances
+            .keys()
+            .map(|token| (*token, prices[token]))
+            .collect(),
+        orders: ring_orders,
+    })
+}
+
+/// The amounts the settlement exchanges with the orders of a pair, if it is cleared at a rate
+/// of units of the buy token per `sell_token`. Amounts are rounded in favour of the settlement.
+struct Volumes {
+    /// The amount of `sell_token` received from the orders selling it.
+    sold: BigInt,
+    /// The amount of `sell_token` paid to the opposite orders.
+    bought: BigInt,
+    /// The amount of the buy token paid to the orders selling `sell_token`.
+    paid: BigInt,
+    /// The amount of the buy token received from the opposite orders.
+    received: BigInt,
+}
+
+impl Volumes {
+    fn new(orders: &[(usize, OrderModel)], sell_token: H160, rate: &BigRational) -> Self {
+        let mut volumes = Volumes {
+            sold: BigInt::zero(),
+            bought: BigInt::zero(),
+            paid: BigInt::zero(),
+            received: BigInt::zero(),
+        };
+        for (_, order) in orders {
+            let sell_amount = order.sell_amount.to_big_rational();
+            let buy_amount = order.buy_amount.to_big_rational();
+            match (order.sell_token == sell_token, order.is_sell_order) {
+                (true, true) => {
+                    volumes.sold += sell_amount.to_integer();
+                    volumes.paid += (sell_amount * rate).floor().to_integer();
+                }
+                (true, false) => {
+                    volumes.sold += (&buy_amount / rate).ceil().to_integer();
+                    volumes.paid += buy_amount.to_integer();
+                }
+                (false, true) => {
+                    volumes.bought += (&sell_amount / rate).floor().to_integer();
+                    volumes.received += sell_amount.to_integer();
+                }
+                (false, false) => {
+                    volumes.bought += buy_amount.to_integer();
+                    volumes.received += (buy_amount * rate).ceil().to_integer();
+                }
+            }
+        }
+        volumes
+    }
+
+    /// The amount of `sell_token` which the opposite orders don't buy.
+    fn excess(&self) -> BigInt {
+        &self.sold - &self.bought
+    }
+}
+
+/// Whether the order accepts a clearing price of `rate` units of the other token per
+/// `token`.
+fn satisfies_limit_price(order: &OrderModel, token: H160, rate: &BigRational) -> bool {
+    if !rate.is_positive() {
+        return false;
+    }
+    let rate = if order.sell_token == token {
+        rate.clone()
+    } else {
+        BigRational::one() / rate
+    };
+    order.buy_amount.to_big_rational() <= order.sell_amount.to_big_rational() * rate
+}
+
+fn to_u256_floor(amount: &BigRational) -> Option<U256> {
+    big_int_to_u256(&amount.floor().to_integer()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::batch_auction_model::{
+        AmmModel, AmmParameters, ConstantProductPoolParameters, CostModel, FeeModel,
+    };
+    use crate::solve::amm_solver::AmmRouter;
+    use maplit::btreemap;
+
+    /// Matches the orders of every pair, with the residuals traded against the amms.
+    async fn match_orders(
+        orders: &[(usize, OrderModel)],
+        tokens: &BTreeMap<H160, TokenInfoModel>,
+        amm_router: &AmmRouter,
+    ) -> Vec<CowMatch> {
+        let mut cow_matches = Vec::new();
+        for ((token_a, token_b), orders) in orders_by_pair(orders) {
+            let quote = |sell_token, buy_token, amount| {
+                futures::future::ready(
+                    amm_router
+                        .quote_sell(sell_token, buy_token, amount)
+                        .map(Residual::Amm),
+                )
+            };
+            cow_matches.extend(match_pair(token_a, token_b, &orders, tokens, quote).await);
+        }
+        cow_matches
+    }
+
+    fn amm_router(token_a: H160, token_b: H160) -> AmmRouter {
+        AmmRouter::new(
+            btreemap! {
+                0 => AmmModel {
+                    parameters: AmmParameters::ConstantProduct(ConstantProductPoolParameters {
+                        reserves: btreemap! {
+                            token_a => 1_000_000.into(),
+                            token_b => 1_000_000.into(),
+                        },
+                    }),
+                    fee: BigRational::new(3.into(), 1000.into()),
+                    cost: CostModel::default(),
+                    mandatory: false,
+                },
+            },
+            &BTreeMap::new(),
+        )
+    }
+
+    fn sell_order(
+        sell_token: H160,
+        buy_token: H160,
+        sell_amount: u64,
+        buy_amount: u64,
+    ) -> OrderModel {
+        OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: sell_amount.into(),
+            buy_amount: buy_amount.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        }
+    }
+
+    fn tokens(token_a: H160, token_b: H160) -> BTreeMap<H160, TokenInfoModel> {
+        btreemap! {
+            token_a => TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+            token_b => TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn opposite_orders_are_matched_at_the_external_price() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 1_000, 900)),
+            (1, sell_order(token_b, token_a, 1_000, 950)),
+            // doesn't accept the external price
+            (2, sell_order(token_a, token_b, 1_000, 1_100)),
+        ];
+        let cow_matches =
+            match_orders(&orders, &tokens(token_a, token_b), &AmmRouter::default()).await;
+
+        assert_eq!(cow_matches.len(), 1);
+        let cow_match = &cow_matches[0];
+        assert!(cow_match.residual.is_none());
+        assert_eq!(cow_match.sell_amount(), 1_000.into());
+        assert_eq!(cow_match.buy_amount(), 1_000.into());
+        let indices: Vec<usize> = cow_match.orders.iter().map(|(index, _)| *index).collect();
+        assert_eq!(indices, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn pairs_without_positive_external_prices_are_not_matched() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 1_000, 900)),
+            (1, sell_order(token_b, token_a, 1_000, 950)),
+        ];
+        for token in [token_a, token_b] {
+            let mut tokens = tokens(token_a, token_b);
+            tokens.get_mut(&token).unwrap().external_price = Some(0.0);
+            assert!(match_orders(&orders, &tokens, &AmmRouter::default())
+                .await
+                .is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn residual_is_traded_against_the_amms() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 2_000, 1_900)),
+            (1, sell_order(token_b, token_a, 1_000, 900)),
+        ];
+        let cow_matches = match_orders(
+            &orders,
+            &tokens(token_a, token_b),
+            &amm_router(token_a, token_b),
+        )
+        .await;
+
+        assert_eq!(cow_matches.len(), 1);
+        let cow_match = &cow_matches[0];
+        assert_eq!(cow_match.sell_token, token_a);
+        let residual = cow_match.residual.as_ref().unwrap();
+        assert!(residual.sell_amount() > 990.into());
+        assert!(residual.sell_amount() <= 1_000.into());
+        // Both orders are settled at the uniform clearing price of the match.
+        assert!(cow_match.buy_amount() * 2_000 >= cow_match.sell_amount() * 1_900);
+        assert!(cow_match.sell_amount() * 1_000 >= cow_match.buy_amount() * 900);
+    }
+
+    #[tokio::test]
+    async fn buy_orders_are_cleared_at_the_rate_of_the_residual() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (
+                0,
+                OrderModel {
+                    is_sell_order: false,
+                    ..sell_order(token_a, token_b, 1_100, 1_000)
+                },
+            ),
+            (1, sell_order(token_b, token_a, 500, 450)),
+        ];
+        let cow_matches = match_orders(
+            &orders,
+            &tokens(token_a, token_b),
+            &amm_router(token_a, token_b),
+        )
+        .await;
+
+        assert_eq!(cow_matches.len(), 1);
+        let cow_match = &cow_matches[0];
+        assert_eq!(cow_match.sell_token, token_a);
+        let residual = cow_match.residual.as_ref().unwrap();
+        // The settlement receives enough of both tokens at the rate of the residual.
+        let (residual_sell_amount, residual_buy_amount) = cow_match.clearing_amounts();
+        let paid_by_buy_order =
+            (1_000 * residual_sell_amount.as_u64()).div_ceil(residual_buy_amount.as_u64());
+        let received_by_sell_order =
+            500 * residual_sell_amount.as_u64() / residual_buy_amount.as_u64();
+        assert!(paid_by_buy_order >= received_by_sell_order + residual.sell_amount().as_u64());
+        assert!(residual.buy_amount() >= 500.into());
+    }
+
+    #[tokio::test]
+    async fn residual_is_quoted_until_it_agrees_with_the_clearing_rate() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 2_000, 1_800)),
+            (1, sell_order(token_b, token_a, 1_000, 900)),
+        ];
+        let mut quotes = Vec::new();
+        // External liquidity paying 0.95 b per a.
+        let quote = |_, _, amount: U256| {
+            quotes.push(amount);
+            futures::future::ready(Some(Residual::ZeroEx(SwapResponse {
+                sell_amount: amount,
+                buy_amount: amount * 95 / 100,
+                ..Default::default()
+            })))
+        };
+        let cow_match = match_pair(token_a, token_b, &orders, &tokens(token_a, token_b), quote)
+            .await
+            .unwrap();
+
+        // At the external rate 1000 a are left over, but the opposite order buys more of
+        // them at the lower rate of the residual.
+        assert_eq!(quotes, vec![1_000.into(), 948.into(), 947.into()]);
+        assert_eq!(cow_match.clearing_amounts(), (947.into(), 899.into()));
+        assert_eq!(cow_match.cow_sell_amount, 1_053.into());
+        assert_eq!(cow_match.cow_buy_amount, 1_000.into());
+    }
+
+    #[test]
+    fn rings_are_cleared_at_the_external_prices() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let mut tokens = tokens(token_a, token_b);
+        tokens.insert(
+            token_c,
+            TokenInfoModel {
+                external_price: Some(2.0),
+                ..Default::default()
+            },
+        );
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 1_000, 900)),
+            (
+                1,
+                OrderModel {
+                    allow_partial_fill: true,
+                    ..sell_order(token_b, token_c, 3_000, 1_400)
+                },
+            ),
+            (2, sell_order(token_c, token_a, 500, 950)),
+        ];
+
+        let rings = match_rings(&orders, &tokens);
+        assert_eq!(rings.len(), 1);
+        let ring = &rings[0];
+        assert_eq!(ring.prices.len(), 3);
+        let executed: Vec<(usize, U256, U256)> = ring
+            .orders
+            .iter()
+            .map(|(index, order)| (*index, order.sell_amount, order.buy_amount))
+            .collect();
+        // The partially fillable order is reduced to the value of the other orders.
+        assert_eq!(
+            executed,
+            vec![
+                (0, 1_000.into(), 900.into()),
+                (1, 1_000.into(), 467.into()),
+                (2, 500.into(), 950.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rings_with_larger_fill_or_kill_orders_are_not_matched() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let token_c = H160::from_low_u64_be(3);
+        let mut tokens = tokens(token_a, token_b);
+        tokens.insert(
+            token_c,
+            TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+        );
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 1_000, 900)),
+            (1, sell_order(token_b, token_c, 2_000, 1_800)),
+            (2, sell_order(token_c, token_a, 1_000, 900)),
+        ];
+        assert!(match_rings(&orders, &tokens).is_empty());
+    }
+
+    #[tokio::test]
+    async fn orders_in_one_direction_are_not_matched() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, sell_order(token_a, token_b, 1_000, 900)),
+            (1, sell_order(token_a, token_b, 500, 450)),
+        ];
+        assert!(
+            match_orders(&orders, &tokens(token_a, token_b), &AmmRouter::default())
+                .await
+                .is_empty()
+        );
+    }
+}
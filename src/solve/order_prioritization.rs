@@ -0,0 +1,252 @@
+//! Prioritization of the user orders of an instance, before any liquidity is queried.
+//!
+//! Orders are ranked by their score, i.e. their estimated surplus plus the part of their
+//! execution costs that is covered by their fee, and only the best ones within the budget are
+//! solved for. Orders whose limit price is too far from the external prices rank after all
+//! market orders, so they only take up the budget the market orders leave. Orders without
+//! external prices can not be scored and come last among their class.
+
+use crate::models::batch_auction_model::{OrderModel, TokenInfoModel};
+use crate::solve::market_order::{classify_order, OrderClass};
+use crate::solve::objective::{estimated_order_surplus, external_price};
+use crate::utils::conversions::U256Ext;
+use num::{BigRational, Zero};
+use primitive_types::{H160, U256};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Number of orders that are solved for, if not configured otherwise.
+pub const DEFAULT_ORDER_BUDGET: usize = 10;
+
+/// The components of the score of an order, all values denominated in the native token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrderScore {
+    /// Relative amount by which the sell amount is worth more than the buy amount at the
+    /// external prices. Negative if the limit price is worse than the external prices.
+    pub limit_price_distance: BigRational,
+    /// Value of the sell amount.
+    pub notional_value: BigRational,
+    /// Value of the fee minus the costs of executing the order.
+    pub fee_coverage: BigRational,
+}
+
+impl OrderScore {
+    pub fn value(&self) -> BigRational {
+        &self.limit_price_distance * &self.notional_value + &self.fee_coverage
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    OutsideBudget { rank: usize, budget: usize },
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DropReason::OutsideBudget { rank, budget } => write!(
+                f,
+                "it is ranked {} while only {} orders are solved for",
+                rank, budget
+            ),
+        }
+    }
+}
+
+/// The orders selected for solving, ordered by their score, and the dropped ones.
+#[derive(Clone, Debug, Default)]
+pub struct Prioritization {
+    pub selected: Vec<(usize, OrderModel)>,
+    pub dropped: Vec<(usize, DropReason)>,
+}
+
+/// Scores an order, if the external prices of all involved tokens are known.
+pub fn score_order(
+    order: &OrderModel,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+) -> Option<OrderScore> {
+    let notional_value = value(order.sell_amount, order.sell_token, tokens)?;
+    if notional_value.is_zero() {
+        return None;
+    }
+    let limit_price_distance = estimated_order_surplus(order, tokens)? / &notional_value;
+    let fee_coverage = value(order.fee.amount, order.fee.token, tokens)?
+        - value(order.cost.amount, order.cost.token, tokens)?;
+    Some(OrderScore {
+        limit_price_distance,
+        notional_value,
+        fee_coverage,
+    })
+}
+
+/// Selects at most `budget` orders with the highest scores, where orders that are no market
+/// orders with the given tolerance rank after all market orders. Every dropped order is logged
+/// with its reason.
+pub fn prioritize_orders(
+    orders: Vec<(usize, OrderModel)>,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+    tolerance: &BigRational,
+    budget: usize,
+) -> Prioritization {
+    let mut prioritization = Prioritization::default();
+    let mut ranked = Vec::new();
+    for (index, order) in orders {
+        let is_market_order = match classify_order(&order, tokens, tolerance) {
+            OrderClass::Market | OrderClass::Unknown => true,
+            OrderClass::Limit => false,
+        };
+        let score = score_order(&order, tokens).map(|score| score.value());
+        ranked.push(((is_market_order, score), (index, order)));
+    }
+    // Orders without a score compare less than all scored ones of their class. The sort is
+    // stable, so orders with equal scores keep their order.
+    ranked.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (rank, ((_, score), (index, order))) in ranked.into_iter().enumerate() {
+        if rank < budget {
+            tracing::debug!("Selecting order {} with score {:?}", index, score);
+            prioritization.selected.push((index, order));
+        } else {
+            prioritization
+                .dropped
+                .push((index, DropReason::OutsideBudget { rank, budget }));
+        }
+    }
+    for (index, reason) in &prioritization.dropped {
+        tracing::info!("Dropping order {}, as {}", index, reason);
+    }
+    prioritization
+}
+
+fn value(
+    amount: U256,
+    token: H160,
+    tokens: &BTreeMap<H160, TokenInfoModel>,
+) -> Option<BigRational> {
+    if amount.is_zero() {
+        return Some(BigRational::zero());
+    }
+    Some(amount.to_big_rational() * external_price(tokens, token).ok()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::batch_auction_model::{CostModel, FeeModel};
+    use maplit::btreemap;
+
+    fn tolerance() -> BigRational {
+        BigRational::new(5.into(), 1000.into())
+    }
+
+    fn order(sell_token: H160, buy_token: H160, buy_amount: u64) -> OrderModel {
+        OrderModel {
+            sell_token,
+            buy_token,
+            sell_amount: 1_000.into(),
+            buy_amount: buy_amount.into(),
+            is_sell_order: true,
+            is_liquidity_order: false,
+            allow_partial_fill: false,
+            cost: CostModel::default(),
+            fee: FeeModel {
+                amount: U256::zero(),
+                token: sell_token,
+            },
+        }
+    }
+
+    fn tokens(token_a: H160, token_b: H160) -> BTreeMap<H160, TokenInfoModel> {
+        btreemap! {
+            token_a => TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+            token_b => TokenInfoModel {
+                external_price: Some(1.0),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn orders_with_highest_scores_are_selected() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, order(token_a, token_b, 990)),
+            (1, order(token_a, token_b, 500)),
+            (2, order(token_a, token_b, 900)),
+        ];
+
+        let prioritization =
+            prioritize_orders(orders.clone(), &tokens(token_a, token_b), &tolerance(), 2);
+        let selected: Vec<usize> = prioritization
+            .selected
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(selected, vec![1, 2]);
+        assert_eq!(
+            prioritization.dropped,
+            vec![(0, DropReason::OutsideBudget { rank: 2, budget: 2 })]
+        );
+
+        // Unscored orders keep their order.
+        let prioritization = prioritize_orders(orders, &BTreeMap::new(), &tolerance(), 2);
+        let selected: Vec<usize> = prioritization
+            .selected
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(selected, vec![0, 1]);
+    }
+
+    #[test]
+    fn orders_far_from_the_external_prices_rank_last() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let orders = vec![
+            (0, order(token_a, token_b, 1_100)),
+            (1, order(token_a, token_b, 1_001)),
+        ];
+        let prioritization =
+            prioritize_orders(orders.clone(), &tokens(token_a, token_b), &tolerance(), 10);
+        let selected: Vec<usize> = prioritization
+            .selected
+            .iter()
+            .map(|(index, _)| *index)
+            .collect();
+        assert_eq!(selected, vec![1, 0]);
+        assert!(prioritization.dropped.is_empty());
+
+        let prioritization = prioritize_orders(orders, &tokens(token_a, token_b), &tolerance(), 1);
+        assert_eq!(prioritization.selected[0].0, 1);
+        assert_eq!(
+            prioritization.dropped,
+            vec![(0, DropReason::OutsideBudget { rank: 1, budget: 1 })]
+        );
+    }
+
+    #[test]
+    fn uncovered_costs_lower_the_score() {
+        let token_a = H160::from_low_u64_be(1);
+        let token_b = H160::from_low_u64_be(2);
+        let mut order = order(token_a, token_b, 900);
+        order.fee.amount = 20.into();
+        order.cost = CostModel {
+            amount: 50.into(),
+            token: token_b,
+        };
+        let score = score_order(&order, &tokens(token_a, token_b)).unwrap();
+        assert_eq!(
+            score.notional_value,
+            BigRational::from_integer(1_000.into())
+        );
+        assert_eq!(
+            score.limit_price_distance,
+            BigRational::new(1.into(), 10.into())
+        );
+        assert_eq!(score.fee_coverage, BigRational::from_integer((-30).into()));
+        assert_eq!(score.value(), BigRational::from_integer(70.into()));
+    }
+}
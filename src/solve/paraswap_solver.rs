@@ -1,13 +1,16 @@
 pub mod api;
+pub mod limited_api;
 use anyhow::{anyhow, Result};
 
 use crate::models::batch_auction_model::OrderModel;
 use crate::models::batch_auction_model::TokenInfoModel;
 use api::{DefaultParaswapApi, ParaswapApi, PriceQuery, Root, Side};
 use derivative::Derivative;
+use limited_api::{LimitedParaswapApi, Limits};
 use primitive_types::U256;
-use reqwest::Client;
+use reqwest::{Client, Url};
 use std::collections::BTreeMap;
+use std::sync::Arc;
 
 const REFERRER: &str = "GPv2";
 
@@ -16,23 +19,36 @@ const REFERRER: &str = "GPv2";
 #[derivative(Debug)]
 pub struct ParaswapSolver {
     #[derivative(Debug = "ignore")]
-    client: Box<dyn ParaswapApi + Send + Sync>,
+    client: Arc<dyn ParaswapApi>,
     slippage_bps: u32,
     disabled_paraswap_dexs: Vec<String>,
+    chain_id: u64,
 }
 
 impl ParaswapSolver {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(disabled_paraswap_dexs: Vec<String>, client: Client) -> Self {
+    /// Creates a solver querying the given api, which can be shared among solvers.
+    pub fn new(
+        chain_id: u64,
+        client: Arc<dyn ParaswapApi>,
+        disabled_paraswap_dexs: Vec<String>,
+    ) -> Self {
         Self {
-            client: Box::new(DefaultParaswapApi {
-                client,
-                partner: REFERRER.into(),
-            }),
+            client,
             slippage_bps: 10u32,
             disabled_paraswap_dexs,
+            chain_id,
         }
     }
+
+    /// The api of Paraswap at the given url, limited to send the requests for many orders.
+    pub fn limited_api(base_url: Url, client: Client) -> Arc<dyn ParaswapApi> {
+        let api = DefaultParaswapApi {
+            client,
+            base_url,
+            partner: REFERRER.into(),
+        };
+        Arc::new(LimitedParaswapApi::new(Box::new(api), Limits::default()))
+    }
 }
 
 impl ParaswapSolver {
@@ -61,6 +77,7 @@ impl ParaswapSolver {
             amount,
             side,
             exclude_dexs: Some(self.disabled_paraswap_dexs.clone()),
+            network: self.chain_id,
         };
         let price_response = self.client.get_full_price_info(price_query).await?;
         Ok((price_response, amount))
@@ -4,7 +4,7 @@ use anyhow::Result;
 use derivative::Derivative;
 use primitive_types::{H160, U256};
 use reqwest::{Client, RequestBuilder, Url};
-use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use serde::{de::DeserializeOwned, de::Error, Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 use web3::types::Bytes;
@@ -16,8 +16,6 @@ pub fn debug_bytes(
     formatter.write_fmt(format_args!("0x{}", hex::encode(&bytes.0)))
 }
 
-const BASE_URL: &str = "https://apiv5.paraswap.io";
-
 #[async_trait::async_trait]
 pub trait ParaswapApi: Send + Sync {
     async fn price(&self, query: PriceQuery) -> Result<PriceResponse, ParaswapResponseError>;
@@ -30,14 +28,19 @@ pub trait ParaswapApi: Send + Sync {
 
 pub struct DefaultParaswapApi {
     pub client: Client,
+    pub base_url: Url,
     pub partner: String,
 }
 
+impl DefaultParaswapApi {
+    pub const DEFAULT_URL: &'static str = "https://apiv5.paraswap.io";
+}
+
 #[async_trait::async_trait]
 impl ParaswapApi for DefaultParaswapApi {
     async fn price(&self, query: PriceQuery) -> Result<PriceResponse, ParaswapResponseError> {
         let query_str = format!("{:?}", &query);
-        let url = query.into_url(&self.partner);
+        let url = query.into_url(&self.base_url, &self.partner);
         tracing::debug!("Querying Paraswap API (price) for url {}", url);
         let response_text = self
             .client
@@ -49,29 +52,11 @@ impl ParaswapApi for DefaultParaswapApi {
             .await
             .map_err(ParaswapResponseError::TextFetch)?;
         tracing::debug!("Response from Paraswap API (price): {}", response_text);
-        let raw_response = serde_json::from_str::<RawResponse<PriceResponse>>(&response_text)
-            .map_err(ParaswapResponseError::DeserializeError)?;
-        match raw_response {
-            RawResponse::ResponseOk(response) => Ok(response),
-            RawResponse::ResponseErr { error: message } => match &message[..] {
-                "computePrice Error" => Err(ParaswapResponseError::ComputePrice(
-                    query_str.parse().unwrap(),
-                )),
-                "No routes found with enough liquidity" => {
-                    Err(ParaswapResponseError::InsufficientLiquidity)
-                }
-                "ESTIMATED_LOSS_GREATER_THAN_MAX_IMPACT" => {
-                    Err(ParaswapResponseError::TooMuchSlippageOnQuote)
-                }
-                _ => Err(ParaswapResponseError::UnknownParaswapError(format!(
-                    "uncatalogued Price Query error message {}",
-                    message
-                ))),
-            },
-        }
+        parse_price_response_text(&response_text, &query_str)
     }
     async fn get_full_price_info(&self, query: PriceQuery) -> Result<Root> {
-        let url = query.into_url(&self.partner);
+        let query_str = format!("{:?}", &query);
+        let url = query.into_url(&self.base_url, &self.partner);
         tracing::debug!("Querying Paraswap API (price) for url {}", url);
 
         let response_text = self
@@ -84,10 +69,7 @@ impl ParaswapApi for DefaultParaswapApi {
             .await
             .map_err(ParaswapResponseError::TextFetch)?;
         tracing::debug!("Response from Paraswap API (price): {}", response_text);
-
-        let raw_response = serde_json::from_str::<Root>(&response_text)
-            .map_err(ParaswapResponseError::DeserializeError)?;
-        Ok(raw_response)
+        Ok(parse_price_response_text(&response_text, &query_str)?)
     }
     async fn transaction(
         &self,
@@ -100,7 +82,7 @@ impl ParaswapApi for DefaultParaswapApi {
 
         let query_str = serde_json::to_string(&query).unwrap();
         let response_text = query
-            .into_request(&self.client)
+            .into_request(&self.client, &self.base_url)
             .send()
             .await
             .map_err(ParaswapResponseError::Send)?
@@ -228,6 +210,33 @@ pub enum ParaswapResponseError {
     DeserializeError(#[from] serde_json::Error),
 }
 
+/// Parses the response of the price endpoint, whose errors are reported in the body.
+pub fn parse_price_response_text<T: DeserializeOwned>(
+    response_text: &str,
+    query_str: &str,
+) -> Result<T, ParaswapResponseError> {
+    let raw_response = serde_json::from_str::<RawResponse<T>>(response_text)
+        .map_err(ParaswapResponseError::DeserializeError)?;
+    match raw_response {
+        RawResponse::ResponseOk(response) => Ok(response),
+        RawResponse::ResponseErr { error: message } => match &message[..] {
+            "computePrice Error" => Err(ParaswapResponseError::ComputePrice(
+                query_str.parse().unwrap(),
+            )),
+            "No routes found with enough liquidity" => {
+                Err(ParaswapResponseError::InsufficientLiquidity)
+            }
+            "ESTIMATED_LOSS_GREATER_THAN_MAX_IMPACT" => {
+                Err(ParaswapResponseError::TooMuchSlippageOnQuote)
+            }
+            _ => Err(ParaswapResponseError::UnknownParaswapError(format!(
+                "uncatalogued Price Query error message {}",
+                message
+            ))),
+        },
+    }
+}
+
 fn parse_paraswap_response_text(
     response_text: &str,
     query_str: &str,
@@ -279,12 +288,13 @@ pub struct PriceQuery {
     pub side: Side,
     /// The list of DEXs to exclude from the computed price route.
     pub exclude_dexs: Option<Vec<String>>,
+    /// Chain id of the network to trade on
+    pub network: u64,
 }
 
 impl PriceQuery {
-    pub fn into_url(self, partner: &str) -> Url {
-        let mut url = Url::parse(BASE_URL)
-            .expect("invalid base url")
+    pub fn into_url(self, base_url: &Url, partner: &str) -> Url {
+        let mut url = base_url
             .join("/prices")
             .expect("unexpectedly invalid URL segment");
 
@@ -301,7 +311,7 @@ impl PriceQuery {
             .append_pair("destDecimals", &self.dest_decimals.to_string())
             .append_pair("amount", &self.amount.to_string())
             .append_pair("side", side)
-            .append_pair("network", "1");
+            .append_pair("network", &self.network.to_string());
 
         if let Some(dexs) = &self.exclude_dexs {
             url.query_pairs_mut()
@@ -388,6 +398,9 @@ pub struct TransactionBuilderQuery {
     pub price_route: Value,
     /// The address of the signer
     pub user_address: H160,
+    /// Chain id of the network to trade on (part of the url, not the body)
+    #[serde(skip_serializing)]
+    pub network: u64,
 }
 
 /// The amounts for buying and selling.
@@ -426,10 +439,9 @@ struct TransactionBuilderQueryWithPartner<'a> {
 }
 
 impl TransactionBuilderQueryWithPartner<'_> {
-    pub fn into_request(self, client: &Client) -> RequestBuilder {
-        let mut url = Url::parse(BASE_URL)
-            .expect("invalid base url")
-            .join("/transactions/1")
+    pub fn into_request(self, client: &Client, base_url: &Url) -> RequestBuilder {
+        let mut url = base_url
+            .join(&format!("/transactions/{}", self.query.network))
             .expect("unexpectedly invalid URL segment");
         url.query_pairs_mut().append_pair("ignoreChecks", "true");
 
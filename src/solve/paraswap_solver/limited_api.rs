@@ -0,0 +1,319 @@
+//! A wrapper of the Paraswap api, which allows to query it for many orders at once.
+//!
+//! Paraswap rejects requests when they arrive too quickly, so the wrapper bounds the number of
+//! requests in flight, spaces them out with a token bucket, and retries the ones failing for
+//! transient reasons after a backoff.
+
+use super::api::{
+    ParaswapApi, ParaswapResponseError, PriceQuery, PriceResponse, Root, TransactionBuilderQuery,
+    TransactionBuilderResponse,
+};
+use anyhow::Result;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Instant};
+
+/// Limits of the requests sent to Paraswap.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    pub max_concurrent_requests: usize,
+    /// Number of requests that can be sent at once, before the rate applies.
+    pub burst: usize,
+    pub requests_per_second: f64,
+    pub max_retries: u32,
+    /// Backoff before the first retry, which doubles with every further retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 10,
+            burst: 10,
+            requests_per_second: 10.0,
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+pub struct LimitedParaswapApi {
+    inner: Box<dyn ParaswapApi>,
+    requests: Semaphore,
+    bucket: TokenBucket,
+    max_retries: u32,
+    initial_backoff: Duration,
+}
+
+impl LimitedParaswapApi {
+    pub fn new(inner: Box<dyn ParaswapApi>, limits: Limits) -> Self {
+        Self {
+            inner,
+            requests: Semaphore::new(limits.max_concurrent_requests),
+            bucket: TokenBucket::new(limits.burst, limits.requests_per_second),
+            max_retries: limits.max_retries,
+            initial_backoff: limits.initial_backoff,
+        }
+    }
+
+    async fn request<T, E, F, Fut>(
+        &self,
+        is_transient: impl Fn(&E) -> bool,
+        request: F,
+    ) -> Result<T, E>
+    where
+        E: Debug,
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut backoff = self.initial_backoff;
+        for _ in 0..self.max_retries {
+            match self.request_once(&request).await {
+                Err(err) if is_transient(&err) => {
+                    tracing::debug!("Retrying paraswap request in {:?}: {:?}", backoff, err);
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        self.request_once(&request).await
+    }
+
+    async fn request_once<T, E, F, Fut>(&self, request: &F) -> Result<T, E>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let _permit = self
+            .requests
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+        self.bucket.acquire().await;
+        request().await
+    }
+}
+
+/// Whether the request may succeed when it is sent again.
+fn is_transient(err: &ParaswapResponseError) -> bool {
+    matches!(
+        err,
+        ParaswapResponseError::PriceChange
+            | ParaswapResponseError::TooMuchSlippageOnQuote
+            | ParaswapResponseError::Send(_)
+    )
+}
+
+#[async_trait::async_trait]
+impl ParaswapApi for LimitedParaswapApi {
+    async fn price(&self, query: PriceQuery) -> Result<PriceResponse, ParaswapResponseError> {
+        self.request(is_transient, || self.inner.price(query.clone()))
+            .await
+    }
+
+    async fn transaction(
+        &self,
+        query: TransactionBuilderQuery,
+    ) -> Result<TransactionBuilderResponse, ParaswapResponseError> {
+        self.request(is_transient, || self.inner.transaction(query.clone()))
+            .await
+    }
+
+    async fn get_full_price_info(&self, query: PriceQuery) -> Result<Root> {
+        self.request(
+            |err: &anyhow::Error| matches!(err.downcast_ref(), Some(err) if is_transient(err)),
+            || self.inner.get_full_price_info(query.clone()),
+        )
+        .await
+    }
+}
+
+/// A token bucket, which holds up to `capacity` tokens and is refilled continuously.
+struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(capacity: usize, refill_per_second: f64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_per_second,
+            state: Mutex::new((capacity as f64, Instant::now())),
+        }
+    }
+
+    /// Waits until a token is available and takes it.
+    async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, last_refill) = &mut *state;
+                let now = Instant::now();
+                *tokens = (*tokens
+                    + now.duration_since(*last_refill).as_secs_f64() * self.refill_per_second)
+                    .min(self.capacity);
+                *last_refill = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / self.refill_per_second)
+            };
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solve::paraswap_solver::api::{parse_price_response_text, Side};
+    use primitive_types::H160;
+    use serde::de::DeserializeOwned;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const SLIPPAGE_ERROR: &str = r#"{"error":"ESTIMATED_LOSS_GREATER_THAN_MAX_IMPACT"}"#;
+    const LIQUIDITY_ERROR: &str = r#"{"error":"No routes found with enough liquidity"}"#;
+
+    /// Fails the first `failures` requests with the given error body, which is parsed like the
+    /// responses of the price endpoint, and tracks the requests in flight.
+    struct FakeApi {
+        failures: usize,
+        error_body: &'static str,
+        calls: Arc<AtomicUsize>,
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    impl FakeApi {
+        fn new(failures: usize, error_body: &'static str) -> Self {
+            Self {
+                failures,
+                error_body,
+                calls: Default::default(),
+                in_flight: Default::default(),
+                max_in_flight: Default::default(),
+            }
+        }
+
+        async fn respond<T: DeserializeOwned + Default>(&self) -> Result<T, ParaswapResponseError> {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            sleep(Duration::from_millis(20)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            match self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                true => parse_price_response_text(self.error_body, "query"),
+                false => Ok(T::default()),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ParaswapApi for FakeApi {
+        async fn price(&self, _: PriceQuery) -> Result<PriceResponse, ParaswapResponseError> {
+            self.respond().await
+        }
+
+        async fn transaction(
+            &self,
+            _: TransactionBuilderQuery,
+        ) -> Result<TransactionBuilderResponse, ParaswapResponseError> {
+            Err(ParaswapResponseError::BuildingTransaction(
+                "transactions are not supported by the fake api".to_string(),
+            ))
+        }
+
+        async fn get_full_price_info(&self, _: PriceQuery) -> Result<Root> {
+            Ok(self.respond().await?)
+        }
+    }
+
+    fn query() -> PriceQuery {
+        PriceQuery {
+            src_token: H160::from_low_u64_be(1),
+            dest_token: H160::from_low_u64_be(2),
+            src_decimals: 18,
+            dest_decimals: 18,
+            amount: 1.into(),
+            side: Side::Sell,
+            exclude_dexs: None,
+            network: 1,
+        }
+    }
+
+    fn limits() -> Limits {
+        Limits {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let fake = FakeApi::new(2, SLIPPAGE_ERROR);
+        let calls = fake.calls.clone();
+        let api = LimitedParaswapApi::new(Box::new(fake), limits());
+        assert!(api.get_full_price_info(query()).await.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        let fake = FakeApi::new(3, SLIPPAGE_ERROR);
+        let calls = fake.calls.clone();
+        let api = LimitedParaswapApi::new(Box::new(fake), limits());
+        assert!(api.price(query()).await.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let fake = FakeApi::new(1, LIQUIDITY_ERROR);
+        let calls = fake.calls.clone();
+        let api = LimitedParaswapApi::new(Box::new(fake), limits());
+        let err = api.get_full_price_info(query()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(ParaswapResponseError::InsufficientLiquidity)
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_are_bounded() {
+        let fake = FakeApi::new(0, SLIPPAGE_ERROR);
+        let max_in_flight = fake.max_in_flight.clone();
+        let api = LimitedParaswapApi::new(
+            Box::new(fake),
+            Limits {
+                max_concurrent_requests: 3,
+                ..limits()
+            },
+        );
+        let requests = (0..10).map(|_| api.price(query()));
+        let start = Instant::now();
+        let results = futures::future::join_all(requests).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 3);
+        // four rounds of at most three requests
+        assert!(start.elapsed() >= Duration::from_millis(80));
+    }
+
+    #[tokio::test]
+    async fn token_bucket_limits_the_rate_after_a_burst() {
+        let bucket = TokenBucket::new(2, 50.0);
+        let start = Instant::now();
+        for _ in 0..2 {
+            bucket.acquire().await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(20));
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(55));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::solve::chain_config::ChainConfig;
+use crate::solve::market_order::DEFAULT_MARKET_ORDER_TOLERANCE;
+use crate::solve::order_prioritization::DEFAULT_ORDER_BUDGET;
+use crate::solve::paraswap_solver::api::ParaswapApi;
+use crate::solve::paraswap_solver::ParaswapSolver;
+use crate::solve::zeroex_solver::ZeroExSolver;
+use anyhow::Result;
+use ethcontract::prelude::{Http, Web3};
+use reqwest::{Client, Url};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Deployment specific settings, which are set up once at startup and shared by all
+/// solve requests.
+#[derive(Clone)]
+pub struct SolverContext {
+    pub web3: Web3<Http>,
+    pub chain: ChainConfig,
+    /// Relative amount by which the limit price of an order may be worse than the external
+    /// prices for it to still count as a market order.
+    pub market_order_tolerance: f64,
+    /// Maximal number of user orders that are solved for per instance.
+    pub order_budget: usize,
+    /// The 0x api of the chain, if 0x is available on it.
+    pub zeroex: Option<Arc<ZeroExSolver>>,
+    /// The Paraswap api of the chain, if Paraswap is available on it. All solve requests share
+    /// its rate limit.
+    pub paraswap: Option<Arc<dyn ParaswapApi>>,
+}
+
+impl SolverContext {
+    pub fn new(node_url: &Url, chain: ChainConfig, zeroex_api_key: Option<String>) -> Result<Self> {
+        let http = Http::new(node_url.as_str())?;
+        // The clients keep their connections open, so they are reused across requests.
+        let zeroex = match chain.supports_zeroex() {
+            true => Some(Arc::new(ZeroExSolver::new(
+                &chain,
+                zeroex_api_key,
+                http_client(Duration::from_secs(5))?,
+            )?)),
+            false => None,
+        };
+        let paraswap = match &chain.paraswap_url {
+            Some(url) => Some(ParaswapSolver::limited_api(
+                url.clone(),
+                http_client(Duration::from_secs(3))?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            web3: Web3::new(http),
+            chain,
+            market_order_tolerance: DEFAULT_MARKET_ORDER_TOLERANCE,
+            order_budget: DEFAULT_ORDER_BUDGET,
+            zeroex,
+            paraswap,
+        })
+    }
+}
+
+fn http_client(timeout: Duration) -> Result<Client> {
+    Ok(Client::builder()
+        .timeout(timeout)
+        .user_agent("gp-v2-services/2.0.0")
+        .build()?)
+}
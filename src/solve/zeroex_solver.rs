@@ -19,8 +19,9 @@
 
 pub mod api;
 
+use crate::solve::chain_config::ChainConfig;
 use crate::solve::zeroex_solver::api::ZeroExApi;
-use anyhow::{ensure, Result};
+use anyhow::{anyhow, Result};
 use reqwest::Client;
 
 use self::api::DefaultZeroExApi;
@@ -32,21 +33,14 @@ pub struct ZeroExSolver {
     pub client: Box<dyn ZeroExApi + Send + Sync>,
 }
 
-/// Chain ID for Mainnet.
-const MAINNET_CHAIN_ID: u64 = 1;
-
 impl ZeroExSolver {
-    pub fn new(chain_id: u64, api_key: Option<String>, client: Client) -> Result<Self> {
-        ensure!(
-            chain_id == MAINNET_CHAIN_ID,
-            "0x solver only supported on Mainnet",
-        );
+    pub fn new(chain: &ChainConfig, api_key: Option<String>, client: Client) -> Result<Self> {
+        let base_url = chain
+            .zeroex_url
+            .clone()
+            .ok_or_else(|| anyhow!("0x solver not supported on chain {}", chain.chain_id))?;
         Ok(Self {
-            client: Box::new(DefaultZeroExApi::new(
-                DefaultZeroExApi::DEFAULT_URL,
-                api_key,
-                client,
-            )?),
+            client: Box::new(DefaultZeroExApi::new(base_url, api_key, client)?),
         })
     }
 }
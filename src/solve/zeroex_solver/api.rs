@@ -133,7 +133,16 @@ impl DefaultZeroExApi {
 #[serde(untagged)]
 enum RawResponse<Ok> {
     ResponseOk(Ok),
-    ResponseErr { reason: String },
+    ResponseErr {
+        reason: String,
+        #[serde(default, rename = "validationErrors")]
+        validation_errors: Vec<ValidationError>,
+    },
+}
+
+#[derive(Deserialize)]
+struct ValidationError {
+    reason: String,
 }
 
 #[derive(Error, Debug)]
@@ -144,6 +153,9 @@ pub enum ZeroExResponseError {
     #[error("uncatalogued error message: {0}")]
     UnknownZeroExError(String),
 
+    #[error("Insufficient asset liquidity")]
+    InsufficientLiquidity,
+
     #[error("Error({0}) for response {1}")]
     DeserializeError(serde_json::Error, String),
 
@@ -184,14 +196,25 @@ impl ZeroExApi for DefaultZeroExApi {
     }
 }
 
-fn parse_zeroex_response_text(
+pub fn parse_zeroex_response_text(
     response_text: &str,
     query: &str,
 ) -> Result<SwapResponse, ZeroExResponseError> {
     match serde_json::from_str::<RawResponse<SwapResponse>>(response_text) {
         Ok(RawResponse::ResponseOk(response)) => Ok(response),
-        Ok(RawResponse::ResponseErr { reason: message }) => match &message[..] {
+        Ok(RawResponse::ResponseErr {
+            reason: message,
+            validation_errors,
+        }) => match &message[..] {
             "Server Error" => Err(ZeroExResponseError::ServerError(format!("{:?}", query))),
+            // The validation of a query fails, if 0x can't find enough liquidity for it.
+            "Validation Failed"
+                if validation_errors
+                    .iter()
+                    .any(|err| err.reason == "INSUFFICIENT_ASSET_LIQUIDITY") =>
+            {
+                Err(ZeroExResponseError::InsufficientLiquidity)
+            }
             _ => Err(ZeroExResponseError::UnknownZeroExError(message)),
         },
         Err(err) => Err(ZeroExResponseError::DeserializeError(